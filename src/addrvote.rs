@@ -0,0 +1,138 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Discovery of the external address from addresses observed by other nodes.
+//!
+//! Every responder votes for the address it saw our request coming from.
+//! Only the latest vote of each voter counts and only recent votes are kept,
+//! so that the guess can follow an address change.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+// Number of latest votes taken into account
+static MAX_VOTES: usize = 64;
+pub static DEFAULT_MIN_VOTES: usize = 5;
+
+/// Majority voting on the external address.
+pub struct AddressVotes<TVoter, TAddr> {
+    min_votes: usize,
+    votes: VecDeque<(TVoter, TAddr)>,
+    best: Option<TAddr>,
+}
+
+impl<TVoter, TAddr> AddressVotes<TVoter, TAddr> {
+    pub fn new(min_votes: usize) -> AddressVotes<TVoter, TAddr> {
+        assert!(min_votes > 0);
+        AddressVotes {
+            min_votes,
+            votes: VecDeque::new(),
+            best: None,
+        }
+    }
+
+    /// Minimum number of votes before guessing the address.
+    pub fn min_votes(&self) -> usize {
+        self.min_votes
+    }
+
+    /// Current best guess, if any.
+    pub fn best(&self) -> Option<&TAddr> {
+        self.best.as_ref()
+    }
+}
+
+impl<TVoter, TAddr> AddressVotes<TVoter, TAddr>
+where
+    TVoter: Eq,
+    TAddr: Hash + Eq + Clone,
+{
+    /// Record the address observed by the voter.
+    ///
+    /// Returns true if the best guess has changed.
+    pub fn add(&mut self, voter: TVoter, address: TAddr) -> bool {
+        self.votes.retain(|(other, _)| *other != voter);
+        self.votes.push_back((voter, address));
+        if self.votes.len() > MAX_VOTES {
+            self.votes.pop_front();
+        }
+        if self.votes.len() < self.min_votes {
+            return false;
+        }
+
+        let mut counts = HashMap::new();
+        for (_, address) in &self.votes {
+            *counts.entry(address).or_insert(0) += 1;
+        }
+        let winner = counts
+            .into_iter()
+            .find(|&(_, count)| count * 2 > self.votes.len())
+            .map(|(address, _)| address.clone());
+        match winner {
+            Some(ref address) if self.best.as_ref() != Some(address) => {
+                self.best = winner;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AddressVotes;
+
+    #[test]
+    fn test_min_votes() {
+        let mut votes = AddressVotes::new(3);
+        assert!(!votes.add(1, "a"));
+        assert!(!votes.add(2, "a"));
+        assert!(votes.best().is_none());
+        assert!(votes.add(3, "a"));
+        assert_eq!(Some(&"a"), votes.best());
+        assert!(!votes.add(4, "a"));
+    }
+
+    #[test]
+    fn test_one_vote_per_node() {
+        let mut votes = AddressVotes::new(3);
+        votes.add(1, "a");
+        for _ in 0..10 {
+            assert!(!votes.add(2, "b"));
+        }
+        assert!(votes.best().is_none());
+    }
+
+    #[test]
+    fn test_majority() {
+        let mut votes = AddressVotes::new(2);
+        votes.add(1, "a");
+        votes.add(2, "b");
+        assert!(votes.best().is_none());
+        assert!(votes.add(3, "a"));
+        assert_eq!(Some(&"a"), votes.best());
+        // A tie keeps the previous guess
+        assert!(!votes.add(4, "b"));
+        assert_eq!(Some(&"a"), votes.best());
+        assert!(votes.add(1, "b"));
+        assert_eq!(Some(&"b"), votes.best());
+    }
+
+    #[test]
+    fn test_old_votes_forgotten() {
+        let mut votes = AddressVotes::new(1);
+        for id in 0..64 {
+            votes.add(id, "a");
+        }
+        for id in 64..96 {
+            assert!(!votes.add(id, "b"));
+        }
+        assert!(votes.add(96, "b"));
+        assert_eq!(Some(&"b"), votes.best());
+    }
+}
@@ -0,0 +1,824 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Asynchronous API on top of `Service`, using tokio.
+//!
+//! The network is abstracted by the `Transport` trait. The request handling
+//! loop runs as a task on a given runtime, lookups are futures sending
+//! queries concurrently. Every query and every lookup has a timeout.
+//!
+//! Everything is cancellable: abort the handle returned by `spawn` to stop
+//! handling requests, drop a lookup future to stop the lookup.
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use super::lookup::Lookup;
+use super::protocol::{Request, RequestPayload, Response, ResponsePayload};
+use super::service::{FindResult, Providers, SampledKeys, Service};
+use super::{GenericAddress, GenericId, GenericNodeTable, Node};
+
+static DEFAULT_QUERY_TIMEOUT_MS: u64 = 5000;
+static DEFAULT_LOOKUP_TIMEOUT_MS: u64 = 30000;
+// Number of nodes sampled at once during a crawl
+static CRAWL_CONCURRENCY: usize = 3;
+
+// Makes the request sent to nodes during a lookup of the ID
+type MakePayload<TId, TData> = fn(TId) -> RequestPayload<TId, TData>;
+
+/// Keys collected by `AsyncService::crawl_keys`.
+#[derive(Clone, Debug)]
+pub struct CrawledKeys<TId: Hash + Eq> {
+    /// Unique keys from all samples.
+    pub keys: HashSet<TId>,
+    /// Number of nodes that returned a sample.
+    pub nodes: usize,
+    /// Sum of total key counts reported by these nodes.
+    pub total: usize,
+}
+
+/// Incoming request with the address the transport received it from.
+pub type Incoming<TId, TAddr, TData> = (TAddr, Request<TId, TAddr, TData>);
+
+/// Network transport for `AsyncService`.
+pub trait Transport<TId, TAddr, TData>: Send + Sync + 'static {
+    /// Address of this node as seen by other nodes.
+    fn local_address(&self) -> TAddr;
+    /// Send a request to the node, resolves to its response.
+    ///
+    /// Resolves to `None` on failure. Does not need to time out.
+    fn send_request(
+        &self,
+        node: &Node<TId, TAddr>,
+        request: Request<TId, TAddr, TData>,
+    ) -> BoxFuture<'static, Option<Response<TId, TAddr, TData>>>;
+    /// Receive the next incoming request with the address it came from.
+    ///
+    /// Resolves to `None` when the transport is closed.
+    fn recv_request(&self) -> BoxFuture<'static, Option<Incoming<TId, TAddr, TData>>>;
+    /// Send a response to an incoming request to the address it came from.
+    fn send_response(
+        &self,
+        address: &TAddr,
+        response: Response<TId, TAddr, TData>,
+    ) -> BoxFuture<'static, ()>;
+}
+
+/// Asynchronous DHT service.
+///
+/// Cheap to clone, clones share the service and the transport.
+pub struct AsyncService<TId, TAddr, TNodeTable, TData, TTransport>
+where
+    TId: GenericId,
+    TNodeTable: GenericNodeTable<TId, TAddr>,
+    TData: Send + Sync + Clone,
+{
+    service: Arc<Mutex<Service<TId, TAddr, TNodeTable, TData>>>,
+    transport: Arc<TTransport>,
+    query_timeout: Duration,
+    lookup_timeout: Duration,
+}
+
+impl<TId, TAddr, TNodeTable, TData, TTransport> Clone
+    for AsyncService<TId, TAddr, TNodeTable, TData, TTransport>
+where
+    TId: GenericId,
+    TNodeTable: GenericNodeTable<TId, TAddr>,
+    TData: Send + Sync + Clone,
+{
+    fn clone(&self) -> Self {
+        AsyncService {
+            service: self.service.clone(),
+            transport: self.transport.clone(),
+            query_timeout: self.query_timeout,
+            lookup_timeout: self.lookup_timeout,
+        }
+    }
+}
+
+impl<TId, TAddr, TNodeTable, TData, TTransport>
+    AsyncService<TId, TAddr, TNodeTable, TData, TTransport>
+where
+    TId: GenericId + 'static,
+    TAddr: GenericAddress + 'static,
+    TNodeTable: GenericNodeTable<TId, TAddr> + 'static,
+    TData: Send + Sync + Clone + 'static,
+    TTransport: Transport<TId, TAddr, TData>,
+{
+    /// Create an asynchronous service from a service and a transport.
+    pub fn new(
+        service: Service<TId, TAddr, TNodeTable, TData>,
+        transport: TTransport,
+    ) -> AsyncService<TId, TAddr, TNodeTable, TData, TTransport> {
+        AsyncService {
+            service: Arc::new(Mutex::new(service)),
+            transport: Arc::new(transport),
+            query_timeout: Duration::from_millis(DEFAULT_QUERY_TIMEOUT_MS),
+            lookup_timeout: Duration::from_millis(DEFAULT_LOOKUP_TIMEOUT_MS),
+        }
+    }
+
+    /// Lock the underlying service, e.g. for configuration.
+    ///
+    /// Do not hold the guard across awaits.
+    pub fn service(&self) -> MutexGuard<'_, Service<TId, TAddr, TNodeTable, TData>> {
+        self.service.lock().unwrap()
+    }
+    /// Get the timeout for one query.
+    pub fn query_timeout(&self) -> Duration {
+        self.query_timeout
+    }
+    /// Set the timeout for one query.
+    pub fn set_query_timeout(&mut self, timeout: Duration) {
+        self.query_timeout = timeout;
+    }
+    /// Get the timeout for a whole lookup.
+    pub fn lookup_timeout(&self) -> Duration {
+        self.lookup_timeout
+    }
+    /// Set the timeout for a whole lookup.
+    pub fn set_lookup_timeout(&mut self, timeout: Duration) {
+        self.lookup_timeout = timeout;
+    }
+
+    /// Start handling incoming requests as a task on the runtime.
+    ///
+    /// Abort the returned handle to stop.
+    pub fn spawn(&self, runtime: &Handle) -> JoinHandle<()> {
+        runtime.spawn(self.clone().serve())
+    }
+
+    /// Handle incoming requests until the transport is closed.
+    pub async fn serve(self) {
+        while let Some((source, request)) = self.transport.recv_request().await {
+            let response = {
+                let mut service = self.service();
+                let this = self.this_node(&service);
+                service.handler_mut().handle_request(request, &source, this)
+            };
+            if let Some(response) = response {
+                self.transport.send_response(&source, response).await;
+            }
+        }
+        debug!("Transport closed, not handling requests any more");
+    }
+
+    /// Ping a node, returns true if it responded.
+    pub async fn ping(&self, node: &Node<TId, TAddr>) -> bool {
+        self.query(node, RequestPayload::Ping).await.is_some()
+    }
+
+    /// Find nodes closest to the ID in the network.
+    pub async fn find_node(&self, id: &TId) -> Vec<Node<TId, TAddr>> {
+        match self
+            .lookup(id, RequestPayload::FindNode, &mut Vec::new())
+            .await
+        {
+            FindResult::ClosestNodes(nodes) => nodes,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Find a value in the network, or the closest nodes if not found.
+    pub async fn find_value(&self, id: &TId) -> FindResult<TId, TAddr, TData> {
+        self.lookup(id, RequestPayload::FindValue, &mut Vec::new())
+            .await
+    }
+
+    /// Store a value on a node.
+    ///
+    /// Asks the node for a write token first. Returns true if the node
+    /// accepted the value.
+    pub async fn store(&self, node: &Node<TId, TAddr>, id: &TId, value: TData) -> bool {
+        let token = match self.query(node, RequestPayload::FindNode(id.clone())).await {
+            Some(Response {
+                token: Some(token), ..
+            }) => token,
+            _ => return false,
+        };
+        let payload = RequestPayload::Store(id.clone(), value, token);
+        match self.query(node, payload).await {
+            Some(response) => !matches!(response.payload, ResponsePayload::Error(..)),
+            None => false,
+        }
+    }
+
+    /// Announce this node as a provider of the ID on a node.
+    ///
+    /// Asks the node for a write token first. Returns true if the node
+    /// accepted the announcement.
+    pub async fn add_provider(&self, node: &Node<TId, TAddr>, id: &TId) -> bool {
+        let token = match self
+            .query(node, RequestPayload::GetProviders(id.clone()))
+            .await
+        {
+            Some(Response {
+                token: Some(token), ..
+            }) => token,
+            _ => return false,
+        };
+        let payload = RequestPayload::AddProvider(id.clone(), token);
+        match self.query(node, payload).await {
+            Some(response) => !matches!(response.payload, ResponsePayload::Error(..)),
+            None => false,
+        }
+    }
+
+    /// Find providers of the ID in the network.
+    ///
+    /// Returns providers found on all queried nodes and the closest nodes.
+    pub async fn get_providers(&self, id: &TId) -> Providers<TId, TAddr> {
+        let mut providers = Vec::new();
+        let nodes = match self
+            .lookup(id, RequestPayload::GetProviders, &mut providers)
+            .await
+        {
+            FindResult::ClosestNodes(nodes) => nodes,
+            _ => Vec::new(),
+        };
+        (providers, nodes)
+    }
+
+    /// Ask a node for a random sample of its keys (BEP 51).
+    ///
+    /// Also returns nodes closest to the target, known to that node.
+    pub async fn sample_keys(
+        &self,
+        node: &Node<TId, TAddr>,
+        target: &TId,
+    ) -> Option<SampledKeys<TId, TAddr>> {
+        let payload = RequestPayload::SampleKeys(target.clone());
+        match self.query(node, payload).await?.payload {
+            ResponsePayload::KeysSampled(sample, nodes) => Some((sample, self.not_banned(nodes))),
+            _ => None,
+        }
+    }
+
+    /// Collect key samples from the network.
+    ///
+    /// Looks up the given number of random IDs and samples keys on all
+    /// nodes found. Nodes returned in sample replies are sampled as well,
+    /// so that the crawl walks the ID space. Each node is asked only once
+    /// and at most `max_nodes` nodes are asked. The crawl stops after the
+    /// lookup timeout, returning the keys collected so far.
+    pub async fn crawl_keys(&self, targets: usize, max_nodes: usize) -> CrawledKeys<TId> {
+        let mut result = CrawledKeys {
+            keys: HashSet::new(),
+            nodes: 0,
+            total: 0,
+        };
+        let run = self.run_crawl(targets, max_nodes, &mut result);
+        if time::timeout(self.lookup_timeout, run).await.is_err() {
+            debug!("Crawl timed out after sampling {} nodes", result.nodes);
+        }
+        result
+    }
+
+    // Samples are recorded as they arrive, so that a timed out crawl
+    // still returns them
+    async fn run_crawl(&self, targets: usize, max_nodes: usize, result: &mut CrawledKeys<TId>) {
+        let mut sampled = HashSet::new();
+        sampled.insert(self.service().node_id().clone());
+        let mut asked = 0;
+        for _ in 0..targets {
+            let target = self.service().node_table().random_id();
+            let target = &target;
+            let mut pending: VecDeque<_> = self
+                .find_node(target)
+                .await
+                .into_iter()
+                .filter(|n| sampled.insert(n.id.clone()))
+                .collect();
+            let mut samples = FuturesUnordered::new();
+            loop {
+                while samples.len() < CRAWL_CONCURRENCY && asked < max_nodes {
+                    let node = match pending.pop_front() {
+                        Some(node) => node,
+                        None => break,
+                    };
+                    asked += 1;
+                    samples.push(async move { self.sample_keys(&node, target).await });
+                }
+                let (sample, nodes) = match samples.next().await {
+                    Some(Some(reply)) => reply,
+                    Some(None) => continue,
+                    None => break,
+                };
+                result.nodes += 1;
+                result.total = result.total.saturating_add(sample.total);
+                result.keys.extend(sample.keys);
+                pending.extend(nodes.into_iter().filter(|n| sampled.insert(n.id.clone())));
+            }
+        }
+    }
+
+    fn this_node(&self, service: &Service<TId, TAddr, TNodeTable, TData>) -> Node<TId, TAddr> {
+        Node {
+            id: service.node_id().clone(),
+            address: self.transport.local_address(),
+        }
+    }
+
+    // Providers found are collected even if the lookup times out
+    async fn lookup(
+        &self,
+        id: &TId,
+        payload: MakePayload<TId, TData>,
+        providers: &mut Vec<Node<TId, TAddr>>,
+    ) -> FindResult<TId, TAddr, TData> {
+        let mut lookup = self.service().new_lookup(id, 1);
+        let run = self.run_lookup(&mut lookup, payload, providers);
+        let result = match time::timeout(self.lookup_timeout, run).await {
+            Ok(Some(values)) => FindResult::Value(values),
+            Ok(None) => lookup.finish(),
+            Err(..) => {
+                debug!("Lookup of {:?} timed out", id);
+                lookup.finish()
+            }
+        };
+        self.service().record_lookup(id, &result);
+        result
+    }
+
+    // Responses are recorded as they arrive, so that the lookup can be
+    // finished with what is known so far, returns the values if found
+    async fn run_lookup(
+        &self,
+        lookup: &mut Lookup<TId, TAddr>,
+        payload: MakePayload<TId, TData>,
+        providers: &mut Vec<Node<TId, TAddr>>,
+    ) -> Option<Vec<TData>> {
+        loop {
+            let batch: Vec<_> = (0..lookup.paths())
+                .flat_map(|path| lookup.next_batch(path).into_iter().map(move |n| (path, n)))
+                .collect();
+            if batch.is_empty() {
+                return None;
+            }
+
+            let target = lookup.target().clone();
+            let mut queries: FuturesUnordered<_> = batch
+                .into_iter()
+                .map(|(path, node)| {
+                    let mut request = payload(target.clone());
+                    if !self.may_send(&node, &request) {
+                        // Still useful for finding other nodes
+                        request = RequestPayload::FindNode(target.clone());
+                    }
+                    async move {
+                        let response = self.query(&node, request).await;
+                        (path, node, response)
+                    }
+                })
+                .collect();
+
+            while let Some((path, node, response)) = queries.next().await {
+                let result = match response.map(|r| r.payload) {
+                    Some(ResponsePayload::ValueFound(values)) => FindResult::Value(values),
+                    Some(ResponsePayload::NodesFound(nodes)) => {
+                        FindResult::ClosestNodes(self.not_banned(nodes))
+                    }
+                    Some(ResponsePayload::ProvidersFound(found, nodes)) => {
+                        for provider in self.not_banned(found) {
+                            if providers.iter().all(|p| p.id != provider.id) {
+                                providers.push(provider);
+                            }
+                        }
+                        FindResult::ClosestNodes(self.not_banned(nodes))
+                    }
+                    Some(ResponsePayload::KeysSampled(_, nodes)) => {
+                        FindResult::ClosestNodes(self.not_banned(nodes))
+                    }
+                    Some(ResponsePayload::NoResult) => FindResult::ClosestNodes(Vec::new()),
+                    Some(ResponsePayload::Error(error)) => {
+                        debug!("Node {:?} returned error {:?}", node.id, error);
+                        FindResult::Nothing
+                    }
+                    None => FindResult::Nothing,
+                };
+                if let Some(values) = lookup.record(path, &node.id, result) {
+                    return Some(values);
+                }
+            }
+        }
+    }
+
+    // Nodes with unknown versions are asked anyway, they answer requests
+    // they do not know with an error
+    fn may_send(&self, node: &Node<TId, TAddr>, payload: &RequestPayload<TId, TData>) -> bool {
+        match payload.feature() {
+            Some(feature) => self
+                .service()
+                .node_table()
+                .version(&node.id)
+                .is_none_or(|v| v.supports(feature)),
+            None => true,
+        }
+    }
+
+    fn not_banned(&self, nodes: Vec<Node<TId, TAddr>>) -> Vec<Node<TId, TAddr>> {
+        let service = self.service();
+        let bans = service.ban_list();
+        nodes.into_iter().filter(|n| !bans.is_banned(n)).collect()
+    }
+
+    // Send a request and process the response, None on failure
+    async fn query(
+        &self,
+        node: &Node<TId, TAddr>,
+        payload: RequestPayload<TId, TData>,
+    ) -> Option<Response<TId, TAddr, TData>> {
+        if !self.may_send(node, &payload) {
+            debug!(
+                "Node {:?} does not support {:?}",
+                node.id,
+                payload.feature()
+            );
+            return None;
+        }
+        let request = {
+            let service = self.service();
+            service.new_request(self.this_node(&service), payload)
+        };
+        let response = time::timeout(
+            self.query_timeout,
+            self.transport.send_request(node, request),
+        )
+        .await;
+
+        let mut service = self.service();
+        match response {
+            Ok(Some(ref response)) if response.responder.id != node.id => {
+                debug!(
+                    "Query to {:?} answered by {:?}",
+                    node.id, response.responder.id
+                );
+                service.report_failure(node);
+                None
+            }
+            Ok(Some(response))
+                if service
+                    .handler_mut()
+                    .handle_response(&response, &node.address) =>
+            {
+                service.report_success(node);
+                Some(response)
+            }
+            Ok(..) => {
+                debug!("Query to {:?} failed", node.id);
+                service.report_failure(node);
+                None
+            }
+            Err(..) => {
+                debug!("Query to {:?} timed out", node.id);
+                service.report_failure(node);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use futures::future::{self, BoxFuture, FutureExt};
+    use tokio::runtime::Handle;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    use super::super::protocol::{Request, RequestPayload, Response, ResponsePayload, Version};
+    use super::super::service::{FindResult, Service};
+    use super::super::{GenericNodeTable, KNodeTable, Node};
+    use super::{AsyncService, Transport};
+
+    type TestService = Service<u64, SocketAddr, KNodeTable<u64, SocketAddr>, String>;
+    type TestRequest = Request<u64, SocketAddr, String>;
+    type TestResponse = Response<u64, SocketAddr, String>;
+    type TestIncoming = (SocketAddr, TestRequest);
+    type TestOutgoing = (SocketAddr, TestResponse);
+    type TestAsyncService =
+        AsyncService<u64, SocketAddr, KNodeTable<u64, SocketAddr>, String, MemoryTransport>;
+    type Network = Arc<Mutex<HashMap<SocketAddr, TestService>>>;
+
+    /// Delivers requests directly to services in the network.
+    ///
+    /// Requests to unknown addresses never get a response.
+    struct MemoryTransport {
+        address: SocketAddr,
+        network: Network,
+        incoming: Arc<tokio::sync::Mutex<mpsc::Receiver<TestIncoming>>>,
+        responses: mpsc::Sender<TestOutgoing>,
+    }
+
+    impl Transport<u64, SocketAddr, String> for MemoryTransport {
+        fn local_address(&self) -> SocketAddr {
+            self.address
+        }
+
+        fn send_request(
+            &self,
+            node: &Node<u64, SocketAddr>,
+            request: TestRequest,
+        ) -> BoxFuture<'static, Option<TestResponse>> {
+            let mut network = self.network.lock().unwrap();
+            match network.get_mut(&node.address) {
+                Some(service) => {
+                    let this = Node {
+                        id: *service.node_id(),
+                        address: node.address,
+                    };
+                    let response =
+                        service
+                            .handler_mut()
+                            .handle_request(request, &self.address, this);
+                    future::ready(response).boxed()
+                }
+                None => future::pending().boxed(),
+            }
+        }
+
+        fn recv_request(&self) -> BoxFuture<'static, Option<TestIncoming>> {
+            let incoming = self.incoming.clone();
+            async move { incoming.lock().await.recv().await }.boxed()
+        }
+
+        fn send_response(
+            &self,
+            address: &SocketAddr,
+            response: TestResponse,
+        ) -> BoxFuture<'static, ()> {
+            let responses = self.responses.clone();
+            let address = *address;
+            async move {
+                responses.send((address, response)).await.unwrap();
+            }
+            .boxed()
+        }
+    }
+
+    fn address(id: u64) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 8000 + id as u16))
+    }
+
+    fn node(id: u64) -> Node<u64, SocketAddr> {
+        Node {
+            id,
+            address: address(id),
+        }
+    }
+
+    fn new_service(id: u64, known: &[u64]) -> TestService {
+        let mut service = Service::new_with_id(KNodeTable::new_with_details(id, 8, 16), id);
+        for other in known {
+            service.node_table_mut().update(&node(*other));
+        }
+        service
+    }
+
+    /// Nodes 2 to 9 form a chain, each knowing the next one, node 9 stores
+    /// the value. Our node 1 only knows node 2.
+    fn setup() -> (
+        TestAsyncService,
+        mpsc::Sender<TestIncoming>,
+        mpsc::Receiver<TestOutgoing>,
+    ) {
+        let mut network = HashMap::new();
+        for id in 2..10 {
+            let known: Vec<u64> = if id < 9 { vec![id + 1] } else { vec![] };
+            network.insert(address(id), new_service(id, &known));
+        }
+        network
+            .get_mut(&address(9))
+            .unwrap()
+            .stored_data_mut()
+            .insert(42, "value".to_string());
+
+        let (requests_tx, requests_rx) = mpsc::channel(1);
+        let (responses_tx, responses_rx) = mpsc::channel(1);
+        let transport = MemoryTransport {
+            address: address(1),
+            network: Arc::new(Mutex::new(network)),
+            incoming: Arc::new(tokio::sync::Mutex::new(requests_rx)),
+            responses: responses_tx,
+        };
+        let mut svc = AsyncService::new(new_service(1, &[2]), transport);
+        svc.set_query_timeout(Duration::from_millis(20));
+        (svc, requests_tx, responses_rx)
+    }
+
+    #[tokio::test]
+    async fn test_find_value() {
+        let (svc, _, _) = setup();
+        match svc.find_value(&42).await {
+            FindResult::Value(values) => assert_eq!(vec!["value"], values),
+            other => panic!("wrong result {:?}", other),
+        }
+        // Contacted nodes are remembered
+        assert!(svc.service().node_table().len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_find_node_and_ping() {
+        let (svc, _, _) = setup();
+        let nodes = svc.find_node(&40).await;
+        assert_eq!(8, nodes[0].id);
+        assert!(svc.ping(&node(5)).await);
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let (svc, _, _) = setup();
+        assert!(svc.store(&node(5), &7, "stored".to_string()).await);
+        match svc.find_value(&7).await {
+            FindResult::Value(values) => assert_eq!(vec!["stored"], values),
+            other => panic!("wrong result {:?}", other),
+        }
+        assert!(!svc.store(&node(100), &7, "lost".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn test_providers() {
+        let (svc, _, _) = setup();
+        let (providers, nodes) = svc.get_providers(&40).await;
+        assert!(providers.is_empty());
+        assert!(!nodes.is_empty());
+
+        assert!(svc.add_provider(&node(5), &40).await);
+        let (providers, _) = svc.get_providers(&40).await;
+        assert_eq!(vec![1], providers.iter().map(|n| n.id).collect::<Vec<_>>());
+        assert!(!svc.add_provider(&node(100), &40).await);
+    }
+
+    #[tokio::test]
+    async fn test_sample_keys() {
+        let (svc, _, _) = setup();
+        let (sample, nodes) = svc.sample_keys(&node(9), &40).await.unwrap();
+        assert_eq!(vec![42], sample.keys);
+        assert_eq!(1, sample.total);
+        assert!(nodes.iter().all(|n| n.id != 9));
+        assert!(svc.sample_keys(&node(100), &40).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_features() {
+        let (svc, _, _) = setup();
+        {
+            let mut service = svc.service();
+            service.node_table_mut().update(&node(5));
+            let old = Version {
+                number: 1,
+                features: Vec::new(),
+            };
+            service.node_table_mut().set_version(&5, old);
+        }
+        assert!(svc.sample_keys(&node(5), &40).await.is_none());
+        assert!(!svc.add_provider(&node(5), &40).await);
+        // Lookups ask such nodes for other nodes instead
+        let (providers, nodes) = svc.get_providers(&40).await;
+        assert!(providers.is_empty());
+        assert_eq!(8, nodes[0].id);
+    }
+
+    #[tokio::test]
+    async fn test_crawl_keys() {
+        let (svc, _, _) = setup();
+        assert!(svc.store(&node(5), &7, "stored".to_string()).await);
+        let crawled = svc.crawl_keys(2, 100).await;
+        assert_eq!(
+            vec![7, 42].into_iter().collect::<HashSet<_>>(),
+            crawled.keys
+        );
+        assert_eq!(8, crawled.nodes);
+        assert_eq!(2, crawled.total);
+    }
+
+    #[tokio::test]
+    async fn test_crawl_keys_walks_sample_replies() {
+        let (svc, _, _) = setup();
+        {
+            // Extend the chain beyond what a single lookup returns, with
+            // every node knowing both neighbours
+            let mut network = svc.transport.network.lock().unwrap();
+            for id in 3..10 {
+                let mut table = network.get_mut(&address(id)).unwrap().node_table_mut();
+                table.update(&node(id - 1));
+                if id == 9 {
+                    table.update(&node(10));
+                }
+            }
+            for id in 10..40 {
+                let known: Vec<u64> = if id < 39 {
+                    vec![id - 1, id + 1]
+                } else {
+                    vec![id - 1]
+                };
+                network.insert(address(id), new_service(id, &known));
+            }
+        }
+        let crawled = svc.crawl_keys(1, 100).await;
+        assert_eq!(38, crawled.nodes);
+        assert_eq!(1, crawled.total);
+    }
+
+    #[tokio::test]
+    async fn test_crawl_keys_limits() {
+        let (mut svc, _, _) = setup();
+        let crawled = svc.crawl_keys(2, 5).await;
+        assert_eq!(5, crawled.nodes);
+
+        // A node never answering does not hold the crawl
+        svc.set_query_timeout(Duration::from_secs(60));
+        svc.set_lookup_timeout(Duration::from_millis(20));
+        {
+            let mut network = svc.transport.network.lock().unwrap();
+            let service = network.get_mut(&address(2)).unwrap();
+            service.node_table_mut().update(&node(100));
+        }
+        let crawl = svc.crawl_keys(1, 100);
+        assert!(time::timeout(Duration::from_secs(5), crawl).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_query_wrong_responder() {
+        let (svc, _, _) = setup();
+        {
+            let mut network = svc.transport.network.lock().unwrap();
+            network.insert(address(50), new_service(51, &[]));
+        }
+        let impostor = node(50);
+        svc.service().node_table_mut().update(&impostor);
+        svc.service().set_max_failures(1);
+        assert!(!svc.ping(&impostor).await);
+        assert!(!svc.service().node_table().contains(&50));
+        assert!(!svc.service().node_table().contains(&51));
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let (svc, _, _) = setup();
+        let dead = node(100);
+        svc.service().node_table_mut().update(&dead);
+        svc.service().set_max_failures(1);
+        assert!(!svc.ping(&dead).await);
+        assert!(!svc.service().node_table().contains(&100));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_timeout() {
+        let (mut svc, _, _) = setup();
+        svc.set_query_timeout(Duration::from_secs(60));
+        svc.set_lookup_timeout(Duration::from_millis(20));
+        svc.service().node_table_mut().update(&node(100));
+        // Node 2 responds, but node 100 holds the lookup until it times out
+        match svc.find_value(&42).await {
+            FindResult::ClosestNodes(nodes) => {
+                assert_eq!(vec![2], nodes.iter().map(|n| n.id).collect::<Vec<_>>())
+            }
+            other => panic!("wrong result {:?}", other),
+        }
+
+        svc.service().node_table_mut().remove(&2);
+        match svc.find_value(&42).await {
+            FindResult::Nothing => {}
+            other => panic!("wrong result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let (svc, requests, mut responses) = setup();
+        let handle = svc.spawn(&Handle::current());
+
+        let request = svc.service().new_request(node(3), RequestPayload::Ping);
+        requests.send((address(3), request)).await.unwrap();
+        let (destination, response) = responses.recv().await.unwrap();
+        assert_eq!(address(3), destination);
+        assert_eq!(Some(address(3)), response.observed_address);
+        assert_eq!(1, response.responder.id);
+        match response.payload {
+            ResponsePayload::NoResult => {}
+            _ => panic!("wrong payload"),
+        }
+        assert!(svc.service().node_table().contains(&3));
+
+        handle.abort();
+        assert!(handle.await.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,410 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! List of banned nodes.
+//!
+//! Nodes can be banned by ID, by exact address or, for `SocketAddr`, by
+//! IP prefix, either forever or until a given time. Expiry uses wall clock
+//! time, so that the list can be saved and restored with `rustc_serialize`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize as serialize;
+
+use super::{GenericId, Node};
+
+/// Ban list, see module documentation.
+pub struct BanList<TId, TAddr> {
+    ids: HashMap<TId, Option<SystemTime>>,
+    addresses: HashMap<TAddr, Option<SystemTime>>,
+    prefixes: Vec<(net::IpAddr, u8, Option<SystemTime>)>,
+    ip: Option<fn(&TAddr) -> net::IpAddr>,
+}
+
+impl<TId, TAddr> BanList<TId, TAddr> {
+    /// Create an empty ban list.
+    pub fn new() -> BanList<TId, TAddr> {
+        BanList {
+            ids: HashMap::new(),
+            addresses: HashMap::new(),
+            prefixes: Vec::new(),
+            ip: None,
+        }
+    }
+}
+
+impl<TId, TAddr> BanList<TId, TAddr>
+where
+    TId: Hash + Eq,
+    TAddr: Hash + Eq,
+{
+    /// Ban node ID, forever if `duration` is `None`.
+    pub fn ban_id(&mut self, id: TId, duration: Option<Duration>) {
+        self.ids.insert(id, expiry(duration));
+    }
+    /// Ban exact address, forever if `duration` is `None`.
+    pub fn ban_address(&mut self, address: TAddr, duration: Option<Duration>) {
+        self.addresses.insert(address, expiry(duration));
+    }
+    /// Lift a ban on node ID.
+    pub fn unban_id(&mut self, id: &TId) -> bool {
+        self.ids.remove(id).is_some()
+    }
+    /// Lift a ban on address.
+    pub fn unban_address(&mut self, address: &TAddr) -> bool {
+        self.addresses.remove(address).is_some()
+    }
+
+    /// Check if node is banned by its ID or address.
+    pub fn is_banned(&self, node: &Node<TId, TAddr>) -> bool {
+        let now = SystemTime::now();
+        active(self.ids.get(&node.id), now) || self.is_address_banned_at(&node.address, now)
+    }
+    /// Check if address is banned.
+    pub fn is_address_banned(&self, address: &TAddr) -> bool {
+        self.is_address_banned_at(address, SystemTime::now())
+    }
+
+    /// Number of bans, including expired ones not yet purged.
+    pub fn len(&self) -> usize {
+        self.ids.len() + self.addresses.len() + self.prefixes.len()
+    }
+    /// Check if there are no bans.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Forget expired bans.
+    pub fn purge_expired(&mut self) {
+        let now = SystemTime::now();
+        self.ids.retain(|_, expires| active(Some(expires), now));
+        self.addresses
+            .retain(|_, expires| active(Some(expires), now));
+        self.prefixes
+            .retain(|(_, _, expires)| active(Some(expires), now));
+    }
+
+    fn is_address_banned_at(&self, address: &TAddr, now: SystemTime) -> bool {
+        if active(self.addresses.get(address), now) {
+            return true;
+        }
+        match self.ip {
+            Some(ip) => {
+                let ip = ip(address);
+                self.prefixes
+                    .iter()
+                    .any(|&(ref network, len, ref expires)| {
+                        active(Some(expires), now) && prefix_matches(network, len, &ip)
+                    })
+            }
+            None => false,
+        }
+    }
+}
+
+impl<TId, TAddr> Default for BanList<TId, TAddr> {
+    fn default() -> BanList<TId, TAddr> {
+        BanList::new()
+    }
+}
+
+impl<TId> BanList<TId, net::SocketAddr>
+where
+    TId: Hash + Eq,
+{
+    /// Ban all addresses with given IP prefix, e.g. `10.0.0.0/8`.
+    pub fn ban_prefix(&mut self, network: net::IpAddr, prefix_len: u8, duration: Option<Duration>) {
+        assert!(prefix_len <= max_prefix_len(&network));
+        self.ip = Some(net::SocketAddr::ip);
+        self.unban_prefix(&network, prefix_len);
+        self.prefixes.push((network, prefix_len, expiry(duration)));
+    }
+    /// Lift a ban on IP prefix.
+    pub fn unban_prefix(&mut self, network: &net::IpAddr, prefix_len: u8) -> bool {
+        let before = self.prefixes.len();
+        self.prefixes
+            .retain(|&(ref n, len, _)| !(len == prefix_len && prefix_matches(n, len, network)));
+        before != self.prefixes.len()
+    }
+}
+
+fn expiry(duration: Option<Duration>) -> Option<SystemTime> {
+    duration.map(|d| SystemTime::now() + d)
+}
+
+fn active(ban: Option<&Option<SystemTime>>, now: SystemTime) -> bool {
+    match ban {
+        Some(&Some(expires)) => expires > now,
+        Some(&None) => true,
+        None => false,
+    }
+}
+
+fn max_prefix_len(network: &net::IpAddr) -> u8 {
+    match *network {
+        net::IpAddr::V4(..) => 32,
+        net::IpAddr::V6(..) => 128,
+    }
+}
+
+fn prefix_matches(network: &net::IpAddr, prefix_len: u8, ip: &net::IpAddr) -> bool {
+    let (network, ip): (Vec<u8>, Vec<u8>) = match (*network, *ip) {
+        (net::IpAddr::V4(n), net::IpAddr::V4(i)) => (n.octets().to_vec(), i.octets().to_vec()),
+        (net::IpAddr::V6(n), net::IpAddr::V6(i)) => (n.octets().to_vec(), i.octets().to_vec()),
+        _ => return false,
+    };
+    let full = (prefix_len / 8) as usize;
+    let rest = prefix_len % 8;
+    if network[..full] != ip[..full] {
+        return false;
+    }
+    rest == 0 || (network[full] ^ ip[full]) >> (8 - rest) == 0
+}
+
+fn encode_expiry<S: serialize::Encoder>(
+    s: &mut S,
+    expires: &Option<SystemTime>,
+) -> Result<(), S::Error> {
+    s.emit_option(|s| match *expires {
+        Some(expires) => {
+            let secs = expires
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            s.emit_option_some(|s| s.emit_u64(secs))
+        }
+        None => s.emit_option_none(),
+    })
+}
+
+fn encode_entry<S, F>(
+    s: &mut S,
+    kind: &str,
+    value: F,
+    prefix_len: u8,
+    expires: &Option<SystemTime>,
+) -> Result<(), S::Error>
+where
+    S: serialize::Encoder,
+    F: FnOnce(&mut S) -> Result<(), S::Error>,
+{
+    s.emit_struct("Ban", 4, |s| {
+        s.emit_struct_field("kind", 0, |s| s.emit_str(kind))?;
+        s.emit_struct_field("value", 1, value)?;
+        s.emit_struct_field("prefix_len", 2, |s| s.emit_u8(prefix_len))?;
+        s.emit_struct_field("expires", 3, |s| encode_expiry(s, expires))
+    })
+}
+
+impl<TId> serialize::Encodable for BanList<TId, net::SocketAddr>
+where
+    TId: GenericId,
+{
+    fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_seq(self.len(), |s| {
+            let mut idx = 0;
+            for (id, expires) in &self.ids {
+                s.emit_seq_elt(idx, |s| encode_entry(s, "id", |s| id.encode(s), 0, expires))?;
+                idx += 1;
+            }
+            for (address, expires) in &self.addresses {
+                s.emit_seq_elt(idx, |s| {
+                    encode_entry(
+                        s,
+                        "address",
+                        |s| s.emit_str(&address.to_string()),
+                        0,
+                        expires,
+                    )
+                })?;
+                idx += 1;
+            }
+            for &(ref network, len, ref expires) in &self.prefixes {
+                s.emit_seq_elt(idx, |s| {
+                    encode_entry(
+                        s,
+                        "prefix",
+                        |s| s.emit_str(&network.to_string()),
+                        len,
+                        expires,
+                    )
+                })?;
+                idx += 1;
+            }
+            Ok(())
+        })
+    }
+}
+
+fn parse<D: serialize::Decoder, T: FromStr>(d: &mut D, what: &str) -> Result<T, D::Error> {
+    let s = d.read_str()?;
+    match FromStr::from_str(&s) {
+        Ok(value) => Ok(value),
+        Err(..) => {
+            let err = format!("Expected {}, got {}", what, s);
+            Err(d.error(&err))
+        }
+    }
+}
+
+impl<TId> serialize::Decodable for BanList<TId, net::SocketAddr>
+where
+    TId: GenericId,
+{
+    fn decode<D: serialize::Decoder>(d: &mut D) -> Result<BanList<TId, net::SocketAddr>, D::Error> {
+        let mut result = BanList::new();
+        d.read_seq(|d, len| {
+            for idx in 0..len {
+                d.read_seq_elt(idx, |d| {
+                    d.read_struct("Ban", 4, |d| {
+                        let kind = d.read_struct_field("kind", 0, |d| d.read_str())?;
+                        let mut id = None;
+                        let mut ip = None;
+                        let mut address = None;
+                        d.read_struct_field("value", 1, |d| {
+                            match &kind[..] {
+                                "id" => id = Some(TId::decode(d)?),
+                                "address" => address = Some(parse(d, "socket address")?),
+                                "prefix" => ip = Some(parse(d, "IP address")?),
+                                _ => {
+                                    let err = format!("Unknown ban kind {}", kind);
+                                    return Err(d.error(&err));
+                                }
+                            }
+                            Ok(())
+                        })?;
+                        let len = d.read_struct_field("prefix_len", 2, |d| d.read_u8())?;
+                        if let Some(ref ip) = ip {
+                            if len > max_prefix_len(ip) {
+                                let err = format!("Invalid prefix {}/{}", ip, len);
+                                return Err(d.error(&err));
+                            }
+                        }
+                        let expires = d.read_struct_field("expires", 3, |d| {
+                            d.read_option(|d, present| {
+                                if present {
+                                    Ok(Some(UNIX_EPOCH + Duration::from_secs(d.read_u64()?)))
+                                } else {
+                                    Ok(None)
+                                }
+                            })
+                        })?;
+
+                        if let Some(id) = id {
+                            result.ids.insert(id, expires);
+                        } else if let Some(address) = address {
+                            result.addresses.insert(address, expires);
+                        } else if let Some(ip) = ip {
+                            result.ip = Some(net::SocketAddr::ip);
+                            result.prefixes.push((ip, len, expires));
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::time::Duration;
+
+    use rustc_serialize::json;
+
+    use super::super::utils::test;
+    use super::BanList;
+
+    type TestBanList = BanList<test::IdType, net::SocketAddr>;
+
+    #[test]
+    fn test_ban_id_and_address() {
+        let mut bans = TestBanList::new();
+        let node = test::new_node(test::make_id(42));
+        let other = test::new_node_with_port(test::make_id(43), 8009);
+        assert!(!bans.is_banned(&node));
+
+        bans.ban_id(node.id.clone(), None);
+        assert!(bans.is_banned(&node));
+        assert!(!bans.is_banned(&other));
+        assert!(bans.unban_id(&node.id));
+        assert!(!bans.is_banned(&node));
+
+        bans.ban_address(other.address, None);
+        assert!(bans.is_banned(&other));
+        assert!(bans.is_address_banned(&other.address));
+        assert!(!bans.is_banned(&node));
+    }
+
+    #[test]
+    fn test_ban_prefix() {
+        let mut bans = TestBanList::new();
+        bans.ban_prefix("10.1.0.0".parse().unwrap(), 15, None);
+        bans.ban_prefix("2001:db8::".parse().unwrap(), 32, None);
+        for addr in &["10.1.2.3:1", "10.0.0.1:1", "[2001:db8::1]:1"] {
+            assert!(bans.is_address_banned(&addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in &["10.2.0.1:1", "127.0.0.1:1", "[2001:db9::1]:1"] {
+            assert!(!bans.is_address_banned(&addr.parse().unwrap()), "{}", addr);
+        }
+        assert!(bans.unban_prefix(&"10.0.0.0".parse().unwrap(), 15));
+        assert!(!bans.is_address_banned(&"10.1.2.3:1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut bans = TestBanList::new();
+        let node = test::new_node(test::make_id(42));
+        bans.ban_id(node.id.clone(), Some(Duration::from_secs(0)));
+        bans.ban_address(node.address, Some(Duration::from_secs(3600)));
+        assert_eq!(2, bans.len());
+        assert!(bans.is_banned(&node));
+        bans.unban_address(&node.address);
+        assert!(!bans.is_banned(&node));
+        bans.purge_expired();
+        assert!(bans.is_empty());
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut bans = TestBanList::new();
+        bans.ban_id(test::make_id(42), None);
+        bans.ban_address(test::ADDR.parse().unwrap(), Some(Duration::from_secs(3600)));
+        bans.ban_prefix("10.0.0.0".parse().unwrap(), 8, None);
+
+        let j = json::encode(&bans).unwrap();
+        let decoded: TestBanList = json::decode(&j).unwrap();
+        assert_eq!(3, decoded.len());
+        assert!(decoded.is_banned(&test::new_node(test::make_id(42))));
+        assert!(decoded.is_address_banned(&test::ADDR.parse().unwrap()));
+        assert!(decoded.is_address_banned(&"10.2.3.4:80".parse().unwrap()));
+        assert!(!decoded.is_address_banned(&"11.2.3.4:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_decode_bad_kind() {
+        let j = r#"[{"kind":"foo","value":"bar","prefix_len":0,"expires":null}]"#;
+        assert!(json::decode::<TestBanList>(j).is_err());
+    }
+
+    #[test]
+    fn test_decode_bad_prefix_len() {
+        let j = r#"[{"kind":"prefix","value":"10.0.0.0","prefix_len":33,"expires":null}]"#;
+        assert!(json::decode::<TestBanList>(j).is_err());
+        let j = r#"[{"kind":"prefix","value":"::","prefix_len":129,"expires":null}]"#;
+        assert!(json::decode::<TestBanList>(j).is_err());
+        let j = r#"[{"kind":"prefix","value":"::","prefix_len":128,"expires":null}]"#;
+        assert_eq!(1, json::decode::<TestBanList>(j).unwrap().len());
+    }
+}
@@ -7,7 +7,6 @@
 // except according to those terms.
 //
 
-use rand;
 use rand::Rng;
 
 use std::fmt::Debug;
@@ -19,6 +18,8 @@ use rustc_serialize as serialize;
 use rustc_serialize::hex::FromHex;
 use rustc_serialize::hex::ToHex;
 
+use super::protocol::{ResponseError, Version, Want};
+
 /// Generalization of num::BigUint, with hexadecimal encoding and decoding
 pub trait GenericId: Hash + PartialEq + Eq + Ord + Clone + Send + Sync + Debug {
     fn bitxor(&self, other: &Self) -> Self;
@@ -26,6 +27,10 @@ pub trait GenericId: Hash + PartialEq + Eq + Ord + Clone + Send + Sync + Debug {
     fn bits(&self) -> usize;
     /// num::bigint::RandBigInt::gen_biguint
     fn gen(bit_size: usize) -> Self;
+    /// Check if both IDs have the same length, only such IDs are comparable.
+    fn same_length(&self, _other: &Self) -> bool {
+        true
+    }
 
     fn encode<S: serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error>;
     fn decode<D: serialize::Decoder>(d: &mut D) -> Result<Self, D::Error>;
@@ -75,6 +80,9 @@ impl GenericId for Vec<u8> {
     fn is_zero(&self) -> bool {
         self.iter().all(|digit| *digit == 0)
     }
+    fn same_length(&self, other: &Vec<u8>) -> bool {
+        self.len() == other.len()
+    }
     fn bits(&self) -> usize {
         let mut bits = self.len() * 8;
         for digit in self {
@@ -119,9 +127,33 @@ impl GenericId for Vec<u8> {
     }
 }
 
+/// Transport address of a node.
+pub trait GenericAddress: Hash + Eq + Clone + Send + Sync {
+    /// Address of the host, ignoring e.g. the port.
+    ///
+    /// Rate limits and external address votes are per host, so that a host
+    /// cannot get around them by using many ports.
+    fn host(&self) -> Self;
+    /// Address families a node at this address is reachable over.
+    ///
+    /// Find replies only give nodes of families wanted by the caller, see
+    /// `Request::want`.
+    fn family(&self) -> Want;
+}
+
+impl GenericAddress for net::SocketAddr {
+    fn host(&self) -> net::SocketAddr {
+        net::SocketAddr::new(self.ip(), 0)
+    }
+    fn family(&self) -> Want {
+        Want::family_of(self)
+    }
+}
+
 /// Trait representing table with known nodes.
 ///
 /// Keeps some reasonable subset of known nodes passed to `update`.
+/// Introspection methods have defaults based on `nodes`.
 pub trait GenericNodeTable<TId, TAddr>: Send + Sync
 where
     TId: GenericId,
@@ -129,11 +161,168 @@ where
     /// Generate suitable random ID.
     fn random_id(&self) -> TId;
     /// Store or update node in the table.
-    fn update(&mut self, node: &Node<TId, TAddr>) -> bool;
+    fn update(&mut self, node: &Node<TId, TAddr>) -> UpdateResult;
     /// Find given number of node, closest to given ID.
     fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>>;
+    /// Find given number of nodes passing `filter`, closest to given ID.
+    fn find_filtered(
+        &self,
+        id: &TId,
+        count: usize,
+        filter: &dyn Fn(&Node<TId, TAddr>) -> bool,
+    ) -> Vec<Node<TId, TAddr>>
+    where
+        TAddr: Clone,
+    {
+        let mut result: Vec<_> = self.nodes().filter(|n| filter(n)).cloned().collect();
+        result.sort_by_key(|n| n.id.bitxor(id));
+        result.truncate(count);
+        result
+    }
     /// Pop expired or the oldest nodes from table for inspection.
     fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>>;
+    /// Remove node with given ID from the table.
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>>;
+    /// Remove all nodes with given address from the table.
+    fn remove_by_address(&mut self, address: &TAddr) -> Vec<Node<TId, TAddr>>
+    where
+        TAddr: PartialEq,
+    {
+        let ids: Vec<TId> = self
+            .nodes()
+            .filter(|n| n.address == *address)
+            .map(|n| n.id.clone())
+            .collect();
+        ids.iter().filter_map(|id| self.remove(id)).collect()
+    }
+    /// Number of nodes in the table.
+    fn len(&self) -> usize {
+        self.nodes().count()
+    }
+    /// Check if the table has no nodes.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Get node with given ID, `None` if it is not in the table.
+    fn get(&self, id: &TId) -> Option<&Node<TId, TAddr>> {
+        self.nodes().find(|n| n.id == *id)
+    }
+    /// Check if node with given ID is in the table.
+    fn contains(&self, id: &TId) -> bool {
+        self.get(id).is_some()
+    }
+    /// Iterate over all nodes in the table.
+    fn nodes<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Node<TId, TAddr>> + 'a>
+    where
+        TId: 'a,
+        TAddr: 'a;
+    /// Index of the bucket given ID would fall into, if the table has buckets.
+    ///
+    /// `None` for IDs that cannot be in the table, e.g. its own ID.
+    fn bucket_for(&self, _id: &TId) -> Option<usize> {
+        None
+    }
+    /// Statistics for every bucket in the table, empty without buckets.
+    fn bucket_stats(&self) -> Vec<BucketStats> {
+        Vec::new()
+    }
+    /// Remember the version advertised by the node, if it is in the table.
+    ///
+    /// Tables not tracking versions ignore it.
+    fn set_version(&mut self, _id: &TId, _version: Version) {}
+    /// Get the last version advertised by the node.
+    ///
+    /// Allows preferring upgraded nodes, e.g. with `find_filtered`.
+    fn version(&self, _id: &TId) -> Option<&Version> {
+        None
+    }
+    /// Remember the closest nodes to the target found by a lookup.
+    ///
+    /// Used by `estimate_size`, tables not estimating size ignore it.
+    fn record_lookup(&mut self, _target: &TId, _closest: &[Node<TId, TAddr>]) {}
+    /// Estimate the number of nodes in the network, if enough is known.
+    fn estimate_size(&self) -> Option<SizeEstimate> {
+        None
+    }
+}
+
+/// Outcome of storing a node in a node table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateResult {
+    /// Node was not known and has been added.
+    Added,
+    /// Node was already known and has been refreshed.
+    Updated,
+    /// No space left for the node, the table needs cleaning up.
+    Full,
+    /// Node was refused by the table, e.g. by its policy or for an ID out of range.
+    Rejected,
+}
+
+impl UpdateResult {
+    /// Whether the node is in the table after the update.
+    pub fn is_stored(&self) -> bool {
+        match *self {
+            UpdateResult::Added | UpdateResult::Updated => true,
+            UpdateResult::Full | UpdateResult::Rejected => false,
+        }
+    }
+}
+
+/// Statistics of one bucket of a node table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketStats {
+    /// Index of the bucket.
+    pub index: usize,
+    /// Number of nodes in the bucket.
+    pub len: usize,
+    /// Maximum number of nodes in the bucket.
+    pub capacity: usize,
+}
+
+/// Estimated number of nodes in the network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeEstimate {
+    /// Most likely number of nodes.
+    pub estimate: f64,
+    /// Lower bound of the confidence range (roughly 95%).
+    pub low: f64,
+    /// Upper bound of the confidence range (roughly 95%).
+    pub high: f64,
+    /// Number of nodes the estimate is based on.
+    pub samples: usize,
+}
+
+impl SizeEstimate {
+    /// Create an estimate from the number of nodes it is based on.
+    ///
+    /// Node counts are assumed to follow a Poisson distribution, so the
+    /// range narrows with more samples. The low bound is at least `known`,
+    /// the number of nodes known to exist.
+    pub fn new(estimate: f64, samples: usize, known: usize) -> SizeEstimate {
+        assert!(samples > 0);
+        let error = 2.0 / (samples as f64).sqrt();
+        let known = known as f64;
+        SizeEstimate {
+            estimate: estimate.max(known),
+            low: (estimate * (1.0 - error)).max(known),
+            high: (estimate * (1.0 + error)).max(known),
+            samples,
+        }
+    }
+
+    /// Combine estimates, weighting them by the number of samples.
+    pub fn combine(estimates: &[SizeEstimate], known: usize) -> Option<SizeEstimate> {
+        let samples: usize = estimates.iter().map(|e| e.samples).sum();
+        if samples == 0 {
+            return None;
+        }
+        let weighted: f64 = estimates
+            .iter()
+            .map(|e| e.estimate * e.samples as f64)
+            .sum();
+        Some(SizeEstimate::new(weighted / samples as f64, samples, known))
+    }
 }
 
 /// Structure representing a node in system.
@@ -141,10 +330,19 @@ where
 /// Every node has an address (IP and port) and a numeric ID, which is
 /// used to calculate metrics and look up data.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "TId: crate::compact::CompactId, TAddr: ::serde::Serialize",
+        deserialize = "TId: crate::compact::CompactId, TAddr: ::serde::Deserialize<'de>"
+    ))
+)]
 pub struct Node<TId, TAddr> {
     /// Network address of the node.
     pub address: TAddr,
     /// ID of the node.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))]
     pub id: TId,
 }
 
@@ -160,17 +358,29 @@ where
     where
         F: FnOnce(&Node<TId, TAddr>, bool);
     /// Return nodes clothest to the given id.
+    ///
+    /// Fails with the error returned by the queried node.
     fn find_node<F>(&mut self, id: &TId, callback: F)
     where
-        F: FnOnce(Vec<Node<TId, TAddr>>);
+        F: FnOnce(Result<Vec<Node<TId, TAddr>>, ResponseError>);
     /// Find a value in the network.
     ///
-    /// Either returns a value or several clothest nodes.
+    /// Either returns a value or several clothest nodes, or fails with
+    /// the error returned by the queried node.
     fn find_value<F>(&mut self, id: &TId, callback: F)
     where
-        F: FnOnce(Option<Self::TValue>, Vec<Node<TId, TAddr>>);
+        F: FnOnce(Result<(Option<Self::TValue>, Vec<Node<TId, TAddr>>), ResponseError>);
     /// Store a value on a node.
     fn store(&mut self, node: &Node<TId, TAddr>, id: &TId, value: Self::TValue);
+    /// Announce this node as a provider of the ID on a node.
+    fn add_provider(&mut self, node: &Node<TId, TAddr>, id: &TId);
+    /// Find providers of the ID in the network.
+    ///
+    /// Returns providers and several clothest nodes, or fails with the
+    /// error returned by the queried node.
+    fn get_providers<F>(&mut self, id: &TId, callback: F)
+    where
+        F: FnOnce(Result<(Vec<Node<TId, TAddr>>, Vec<Node<TId, TAddr>>), ResponseError>);
 }
 
 impl<TId> serialize::Encodable for Node<TId, net::SocketAddr>
@@ -209,32 +419,51 @@ where
             })?;
 
             let id = d.read_struct_field("id", 1, TId::decode)?;
-            Ok(Node {
-                address: addr,
-                id: id,
-            })
+            Ok(Node { address: addr, id })
         })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use rustc_serialize::json;
+    use rustc_serialize::{json, Decodable, Decoder, Encodable, Encoder};
     use std::net;
 
-    use super::{GenericAPI, Node};
+    use super::super::protocol::{ResponseError, METHOD_UNKNOWN};
+    use super::{GenericAPI, GenericNodeTable, Node, SizeEstimate, UpdateResult};
 
     use super::super::utils::test;
     type TestsIdType = test::IdType;
 
-    #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone)]
     struct SimplifiedNode {
         address: String,
         id: String,
     }
 
+    impl Encodable for SimplifiedNode {
+        fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+            s.emit_struct("SimplifiedNode", 2, |s| {
+                s.emit_struct_field("address", 0, |s2| self.address.encode(s2))?;
+                s.emit_struct_field("id", 1, |s2| self.id.encode(s2))
+            })
+        }
+    }
+
+    impl Decodable for SimplifiedNode {
+        fn decode<D: Decoder>(d: &mut D) -> Result<SimplifiedNode, D::Error> {
+            d.read_struct("SimplifiedNode", 2, |d| {
+                Ok(SimplifiedNode {
+                    address: d.read_struct_field("address", 0, D::read_str)?,
+                    id: d.read_struct_field("id", 1, D::read_str)?,
+                })
+            })
+        }
+    }
+
     struct DummyAPI {
         value: Option<i32>,
+        providers: Vec<Node<TestsIdType, net::SocketAddr>>,
     }
 
     impl GenericAPI<TestsIdType, net::SocketAddr> for DummyAPI {
@@ -247,15 +476,23 @@ mod test {
         }
         fn find_node<F>(&mut self, _id: &TestsIdType, callback: F)
         where
-            F: FnOnce(Vec<Node<TestsIdType, net::SocketAddr>>),
+            F: FnOnce(Result<Vec<Node<TestsIdType, net::SocketAddr>>, ResponseError>),
         {
-            callback(vec![]);
+            callback(Err(ResponseError::new(METHOD_UNKNOWN, "not implemented")));
         }
         fn find_value<F>(&mut self, _id: &TestsIdType, callback: F)
         where
-            F: FnOnce(Option<Self::TValue>, Vec<Node<TestsIdType, net::SocketAddr>>),
+            F: FnOnce(
+                Result<
+                    (
+                        Option<Self::TValue>,
+                        Vec<Node<TestsIdType, net::SocketAddr>>,
+                    ),
+                    ResponseError,
+                >,
+            ),
         {
-            callback(self.value, vec![]);
+            callback(Ok((self.value, vec![])));
         }
         fn store(
             &mut self,
@@ -265,6 +502,83 @@ mod test {
         ) {
             self.value = Some(value);
         }
+        fn add_provider(&mut self, node: &Node<TestsIdType, net::SocketAddr>, _id: &TestsIdType) {
+            self.providers.push(node.clone());
+        }
+        fn get_providers<F>(&mut self, _id: &TestsIdType, callback: F)
+        where
+            F: FnOnce(
+                Result<
+                    (
+                        Vec<Node<TestsIdType, net::SocketAddr>>,
+                        Vec<Node<TestsIdType, net::SocketAddr>>,
+                    ),
+                    ResponseError,
+                >,
+            ),
+        {
+            callback(Ok((self.providers.clone(), vec![])));
+        }
+    }
+
+    /// Table implementing only what the defaults need.
+    struct ListTable {
+        nodes: Vec<Node<TestsIdType, net::SocketAddr>>,
+    }
+
+    impl GenericNodeTable<TestsIdType, net::SocketAddr> for ListTable {
+        fn random_id(&self) -> TestsIdType {
+            test::make_id(1)
+        }
+        fn update(&mut self, node: &Node<TestsIdType, net::SocketAddr>) -> UpdateResult {
+            self.nodes.push(node.clone());
+            UpdateResult::Added
+        }
+        fn find(&self, id: &TestsIdType, count: usize) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            self.find_filtered(id, count, &|_| true)
+        }
+        fn pop_oldest(&mut self) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            vec![]
+        }
+        fn remove(&mut self, id: &TestsIdType) -> Option<Node<TestsIdType, net::SocketAddr>> {
+            let index = self.nodes.iter().position(|n| n.id == *id)?;
+            Some(self.nodes.remove(index))
+        }
+        fn nodes<'a>(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = &'a Node<TestsIdType, net::SocketAddr>> + 'a>
+        where
+            TestsIdType: 'a,
+        {
+            Box::new(self.nodes.iter())
+        }
+    }
+
+    #[test]
+    fn test_node_table_defaults() {
+        let mut table = ListTable { nodes: vec![] };
+        assert!(table.is_empty());
+        table.update(&test::new_node(test::make_id(1)));
+        table.update(&test::new_node_with_port(test::make_id(2), 8009));
+        table.update(&test::new_node(test::make_id(3)));
+
+        assert_eq!(3, table.len());
+        assert!(table.contains(&test::make_id(2)));
+        assert!(table.get(&test::make_id(4)).is_none());
+        assert_eq!(None, table.bucket_for(&test::make_id(2)));
+        assert!(table.bucket_stats().is_empty());
+        assert!(table.version(&test::make_id(2)).is_none());
+
+        let removed = table.remove_by_address(&test::new_node(test::make_id(1)).address);
+        assert_eq!(2, removed.len());
+        assert_eq!(
+            vec![test::make_id(2)],
+            table
+                .find(&test::make_id(1), 10)
+                .into_iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        );
     }
 
     #[test]
@@ -316,12 +630,52 @@ mod test {
         assert_eq!(n.address, n2.address);
     }
 
+    #[test]
+    fn test_size_estimate() {
+        let estimate = SizeEstimate::new(100.0, 100, 0);
+        assert_eq!(100.0, estimate.estimate);
+        assert!((estimate.low - 80.0).abs() < 1e-9);
+        assert!((estimate.high - 120.0).abs() < 1e-9);
+        // Known nodes are the lower bound
+        let estimate = SizeEstimate::new(100.0, 1, 150);
+        assert_eq!(150.0, estimate.estimate);
+        assert_eq!(150.0, estimate.low);
+        assert_eq!(300.0, estimate.high);
+
+        let combined = SizeEstimate::combine(
+            &[
+                SizeEstimate::new(100.0, 1, 0),
+                SizeEstimate::new(200.0, 3, 0),
+            ],
+            0,
+        )
+        .unwrap();
+        assert_eq!(175.0, combined.estimate);
+        assert_eq!(4, combined.samples);
+        assert!(SizeEstimate::combine(&[], 10).is_none());
+    }
+
     #[test]
     fn test_generic_api() {
-        let mut api = DummyAPI { value: None };
+        let mut api = DummyAPI {
+            value: None,
+            providers: vec![],
+        };
         let n = test::new_node(test::make_id(42));
         api.ping(&n, |_node, res| {
             assert!(res);
         });
+        api.store(&n, &test::make_id(1), 42);
+        api.find_value(&test::make_id(1), |res| {
+            assert_eq!(Some(42), res.unwrap().0);
+        });
+        api.find_node(&test::make_id(1), |res| {
+            assert_eq!(METHOD_UNKNOWN, res.unwrap_err().code);
+        });
+        api.add_provider(&n, &test::make_id(1));
+        api.get_providers(&test::make_id(1), |res| {
+            let (providers, _) = res.unwrap();
+            assert_eq!(test::make_id(42), providers[0].id);
+        });
     }
 }
@@ -0,0 +1,200 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Secure node IDs derived from the external IP address.
+//!
+//! See [BEP 42](http://www.bittorrent.org/beps/bep_0042.html) for details.
+//! The first 21 bits of a node ID are a CRC32C of the masked IP address
+//! and a random number, which is also stored in the last byte of the ID.
+
+use std::net;
+
+use rand;
+use rand::Rng;
+
+use super::Node;
+
+/// Size of a secure ID in bytes.
+pub static ID_SIZE: usize = 20;
+
+static V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+static V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Generate a random 160-bit ID compliant with BEP 42 for given IP.
+pub fn generate_id(ip: &net::IpAddr) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let r: u8 = rng.gen();
+    let mut id = vec![0u8; ID_SIZE];
+    rng.fill(&mut id[..]);
+    id[ID_SIZE - 1] = r;
+    let crc = id_crc(ip, r);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+/// Check if ID is compliant with BEP 42 for given IP.
+pub fn is_valid_id(id: &[u8], ip: &net::IpAddr) -> bool {
+    if id.len() != ID_SIZE {
+        return false;
+    }
+    let crc = id_crc(ip, id[ID_SIZE - 1]);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && (id[2] & 0xf8) == ((crc >> 8) as u8 & 0xf8)
+}
+
+/// Check if IP is exempt from ID validation (local and private ranges).
+pub fn is_exempt(ip: &net::IpAddr) -> bool {
+    match *ip {
+        net::IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        net::IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link local fe80::/10
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Validate node for inserting into the node table.
+///
+/// Suitable for `Service::set_node_validator`, which passes nodes with the
+/// address their requests or responses came from.
+pub fn validate_node(node: &Node<Vec<u8>, net::SocketAddr>) -> bool {
+    let ip = node.address.ip();
+    is_exempt(&ip) || is_valid_id(&node.id, &ip)
+}
+
+fn id_crc(ip: &net::IpAddr, r: u8) -> u32 {
+    let r = r & 0x07;
+    match *ip {
+        net::IpAddr::V4(ip) => {
+            let mut masked = ip.octets();
+            for (byte, mask) in masked.iter_mut().zip(V4_MASK.iter()) {
+                *byte &= *mask;
+            }
+            masked[0] |= r << 5;
+            crc32c(&masked)
+        }
+        net::IpAddr::V6(ip) => {
+            let mut masked = [0u8; 8];
+            masked.copy_from_slice(&ip.octets()[..8]);
+            for (byte, mask) in masked.iter_mut().zip(V6_MASK.iter()) {
+                *byte &= *mask;
+            }
+            masked[0] |= r << 5;
+            crc32c(&masked)
+        }
+    }
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    // Castagnoli polynomial, reversed
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+
+    use rustc_serialize::hex::FromHex;
+
+    use super::super::Node;
+    use super::{generate_id, is_exempt, is_valid_id, validate_node};
+
+    // Test vectors from BEP 42
+    static VECTORS: [(&str, &str); 5] = [
+        ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+        ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+        ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+        ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+        ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+    ];
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(0xe306_9283, super::crc32c(b"123456789"));
+    }
+
+    #[test]
+    fn test_is_valid_id_vectors() {
+        for &(ip, id) in VECTORS.iter() {
+            let ip: net::IpAddr = ip.parse().unwrap();
+            let id = id.from_hex().unwrap();
+            assert!(is_valid_id(&id, &ip), "{} {:?}", ip, id);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_id_wrong_ip() {
+        let ip: net::IpAddr = "124.31.75.22".parse().unwrap();
+        let id = VECTORS[1].1.from_hex().unwrap();
+        assert!(!is_valid_id(&id, &ip));
+        assert!(!is_valid_id(&id[..19], &ip));
+    }
+
+    #[test]
+    fn test_generate_id() {
+        for ip in &["124.31.75.21", "2001:db8::1"] {
+            let ip: net::IpAddr = ip.parse().unwrap();
+            for _ in 0..10 {
+                let id = generate_id(&ip);
+                assert_eq!(20, id.len());
+                assert!(is_valid_id(&id, &ip));
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_exempt() {
+        for ip in &["10.1.2.3", "192.168.1.1", "127.0.0.1", "::1", "fd00::1"] {
+            assert!(is_exempt(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["124.31.75.21", "2001:db8::1"] {
+            assert!(!is_exempt(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_validate_node() {
+        let node = Node {
+            id: vec![0u8; 20],
+            address: "124.31.75.21:6881".parse().unwrap(),
+        };
+        assert!(!validate_node(&node));
+        let node = Node {
+            id: vec![0u8; 20],
+            address: "192.168.0.1:6881".parse().unwrap(),
+        };
+        assert!(validate_node(&node));
+        let address: net::SocketAddr = "124.31.75.21:6881".parse().unwrap();
+        let node = Node {
+            id: generate_id(&address.ip()),
+            address,
+        };
+        assert!(validate_node(&node));
+    }
+}
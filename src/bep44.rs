@@ -0,0 +1,349 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Storing arbitrary data in the DHT.
+//!
+//! See [BEP 44](http://www.bittorrent.org/beps/bep_0044.html) for details.
+//! Immutable items are stored under the SHA-1 of their value. Mutable items
+//! are stored under the SHA-1 of the public key and an optional salt, and
+//! are signed together with a sequence number, which must grow with every
+//! update.
+//!
+//! Items are used as values of a `Service` with 20 bytes IDs: get is a find
+//! value request and put is a store request, validated by `validate_put`.
+
+use ed25519_dalek::VerifyingKey;
+use sha1::{Digest, Sha1};
+
+use super::protocol::{ResponseError, Signature, PROTOCOL_ERROR};
+use super::skademlia::Keypair;
+
+/// Maximum size of a value in bytes.
+pub static MAX_VALUE_SIZE: usize = 1000;
+/// Maximum size of a salt in bytes.
+pub static MAX_SALT_SIZE: usize = 64;
+
+/// Value is bigger than `MAX_VALUE_SIZE`.
+pub static MESSAGE_TOO_BIG: u16 = 205;
+/// Signature of a mutable item is invalid.
+pub static INVALID_SIGNATURE: u16 = 206;
+/// Salt is bigger than `MAX_SALT_SIZE`.
+pub static SALT_TOO_BIG: u16 = 207;
+/// Sequence number of the stored item does not match the expected one.
+pub static CAS_MISMATCH: u16 = 301;
+/// Sequence number is less than the one of the stored item.
+pub static SEQUENCE_TOO_OLD: u16 = 302;
+
+/// Item stored in the DHT.
+///
+/// Values are opaque here; in BitTorrent they are bencoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Item {
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+/// Signed item, which can be updated by the owner of the key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MutableItem {
+    pub value: Vec<u8>,
+    /// Salt to store several items with the same key.
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    /// Public key of the owner and the signature.
+    pub signature: Signature,
+    /// Expected sequence number of the stored item (compare-and-swap).
+    ///
+    /// Only meaningful when putting an item.
+    pub cas: Option<i64>,
+}
+
+impl Item {
+    /// ID the item is stored under.
+    pub fn target(&self) -> Vec<u8> {
+        match *self {
+            Item::Immutable(ref value) => immutable_target(value),
+            Item::Mutable(ref item) => item.target(),
+        }
+    }
+    /// Value of the item.
+    pub fn value(&self) -> &[u8] {
+        match *self {
+            Item::Immutable(ref value) => value,
+            Item::Mutable(ref item) => &item.value,
+        }
+    }
+}
+
+impl MutableItem {
+    /// Create an item signed with the key pair.
+    pub fn new(keypair: &Keypair, salt: Vec<u8>, seq: i64, value: Vec<u8>) -> MutableItem {
+        let signature = keypair.sign(&signed_bytes(&salt, seq, &value));
+        MutableItem {
+            value,
+            salt,
+            seq,
+            signature,
+            cas: None,
+        }
+    }
+
+    /// Public key of the owner.
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.signature.public_key
+    }
+
+    /// ID the item is stored under.
+    pub fn target(&self) -> Vec<u8> {
+        mutable_target(self.public_key(), &self.salt)
+    }
+
+    /// Check the signature.
+    pub fn verify(&self) -> bool {
+        match VerifyingKey::from_bytes(self.public_key()) {
+            Ok(key) => {
+                let sig = ed25519_dalek::Signature::from_bytes(&self.signature.signature);
+                let data = signed_bytes(&self.salt, self.seq, &self.value);
+                key.verify_strict(&data, &sig).is_ok()
+            }
+            Err(..) => false,
+        }
+    }
+}
+
+/// ID of an immutable item with the value.
+pub fn immutable_target(value: &[u8]) -> Vec<u8> {
+    Sha1::digest(value).to_vec()
+}
+
+/// ID of a mutable item with the public key and salt.
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt);
+    hasher.finalize().to_vec()
+}
+
+/// Bytes of a mutable item covered by its signature.
+///
+/// Same as in BEP 44: bencoded salt (if any) and sequence number, followed
+/// by the value.
+pub fn signed_bytes(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+/// Check an item before storing it under the target.
+///
+/// `current` is the item already stored under the target, if any.
+/// Suitable for `Service::set_value_validator`.
+pub fn validate_put<TId>(
+    target: &TId,
+    item: &Item,
+    current: Option<&Item>,
+) -> Result<(), ResponseError>
+where
+    TId: AsRef<[u8]>,
+{
+    if item.value().len() > MAX_VALUE_SIZE {
+        return Err(ResponseError::new(MESSAGE_TOO_BIG, "message too big"));
+    }
+    if let Item::Mutable(ref item) = *item {
+        if item.salt.len() > MAX_SALT_SIZE {
+            return Err(ResponseError::new(SALT_TOO_BIG, "salt too big"));
+        }
+        if !item.verify() {
+            return Err(ResponseError::new(INVALID_SIGNATURE, "invalid signature"));
+        }
+    }
+    if item.target() != target.as_ref() {
+        return Err(ResponseError::new(
+            PROTOCOL_ERROR,
+            "item does not match target",
+        ));
+    }
+
+    if let (Item::Mutable(ref item), Some(Item::Mutable(ref current))) = (item, current) {
+        if item.cas.is_some_and(|cas| cas != current.seq) {
+            return Err(ResponseError::new(CAS_MISMATCH, "CAS mismatch"));
+        }
+        if item.seq < current.seq || (item.seq == current.seq && item.value != current.value) {
+            return Err(ResponseError::new(
+                SEQUENCE_TOO_OLD,
+                "sequence number less than current",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+
+    use super::super::protocol::{Request, RequestPayload, ResponsePayload, Version};
+    use super::super::service::Service;
+    use super::super::skademlia::Keypair;
+    use super::super::utils::test;
+    use super::super::KNodeTable;
+    use super::{
+        immutable_target, mutable_target, signed_bytes, validate_put, Item, MutableItem,
+        CAS_MISMATCH, INVALID_SIGNATURE, MESSAGE_TOO_BIG, SALT_TOO_BIG, SEQUENCE_TOO_OLD,
+    };
+
+    fn error_code(target: &[u8], item: &Item, current: Option<&Item>) -> u16 {
+        validate_put(&target.to_vec(), item, current)
+            .unwrap_err()
+            .code
+    }
+
+    #[test]
+    fn test_signed_bytes() {
+        // Test vectors from BEP 44
+        assert_eq!(
+            b"3:seqi1e1:v12:Hello World!".to_vec(),
+            signed_bytes(&[], 1, b"12:Hello World!")
+        );
+        assert_eq!(
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!".to_vec(),
+            signed_bytes(b"foobar", 1, b"12:Hello World!")
+        );
+    }
+
+    #[test]
+    fn test_immutable() {
+        let item = Item::Immutable(b"12:Hello World!".to_vec());
+        // Test vector from BEP 44
+        assert_eq!(
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb",
+            item.target()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        );
+        assert!(validate_put(&item.target(), &item, None).is_ok());
+        assert!(validate_put(&vec![0; 20], &item, None).is_err());
+
+        let big = Item::Immutable(vec![0; 1001]);
+        assert_eq!(MESSAGE_TOO_BIG, error_code(&big.target(), &big, None));
+    }
+
+    #[test]
+    fn test_mutable() {
+        let keypair = Keypair::generate();
+        let item = MutableItem::new(&keypair, b"salt".to_vec(), 1, b"1:a".to_vec());
+        assert!(item.verify());
+        assert_eq!(
+            mutable_target(&keypair.public_key(), b"salt"),
+            item.target()
+        );
+        assert!(item.target() != mutable_target(&keypair.public_key(), b""));
+        let target = item.target();
+        assert!(validate_put(&target, &Item::Mutable(item.clone()), None).is_ok());
+
+        let mut forged = item.clone();
+        forged.seq = 2;
+        assert!(!forged.verify());
+        assert_eq!(
+            INVALID_SIGNATURE,
+            error_code(&target, &Item::Mutable(forged), None)
+        );
+
+        let salty = MutableItem::new(&keypair, vec![0; 65], 1, b"1:a".to_vec());
+        let salty = Item::Mutable(salty);
+        assert_eq!(SALT_TOO_BIG, error_code(&salty.target(), &salty, None));
+
+        // Mutable and immutable targets never match
+        let immutable = Item::Immutable(b"1:a".to_vec());
+        assert!(validate_put(&target, &immutable, None).is_err());
+        assert_ne!(immutable_target(b"1:a"), target);
+    }
+
+    #[test]
+    fn test_sequence_and_cas() {
+        let keypair = Keypair::generate();
+        let item = |seq, value: &[u8]| MutableItem::new(&keypair, Vec::new(), seq, value.to_vec());
+        let current = Item::Mutable(item(5, b"1:a"));
+        let target = current.target();
+
+        let older = Item::Mutable(item(4, b"1:b"));
+        assert_eq!(
+            SEQUENCE_TOO_OLD,
+            error_code(&target, &older, Some(&current))
+        );
+        let same = Item::Mutable(item(5, b"1:b"));
+        assert_eq!(SEQUENCE_TOO_OLD, error_code(&target, &same, Some(&current)));
+        assert!(validate_put(&target, &current, Some(&current)).is_ok());
+        let newer = Item::Mutable(item(6, b"1:b"));
+        assert!(validate_put(&target, &newer, Some(&current)).is_ok());
+
+        let mut swap = item(6, b"1:b");
+        swap.cas = Some(4);
+        assert_eq!(
+            CAS_MISMATCH,
+            error_code(&target, &Item::Mutable(swap.clone()), Some(&current))
+        );
+        swap.cas = Some(5);
+        assert!(validate_put(&target, &Item::Mutable(swap), Some(&current)).is_ok());
+    }
+
+    #[test]
+    fn test_service() {
+        let table = KNodeTable::new_with_details(test::make_id(42), 8, 8);
+        let mut svc: Service<test::IdType, net::SocketAddr, _, Item> =
+            Service::new_with_id(table, test::make_id(42));
+        svc.set_value_validator(validate_put);
+        let this = test::new_node(test::make_id(42));
+        let caller = test::new_node(test::make_id(1));
+        let token = svc.handler_mut().token_for(&caller.address);
+        let keypair = Keypair::generate();
+        let mut put = |item: MutableItem| {
+            let request = Request {
+                caller: caller.clone(),
+                request_id: test::make_id(2),
+                payload: RequestPayload::Store(item.target(), Item::Mutable(item), token.clone()),
+                version: Version::current(),
+                read_only: false,
+                want: None,
+                signature: None,
+            };
+            match svc
+                .handler_mut()
+                .handle_request(request, &caller.address, this.clone())
+                .unwrap()
+                .payload
+            {
+                ResponsePayload::NoResult => None,
+                ResponsePayload::Error(error) => Some(error.code),
+                _ => panic!("wrong payload"),
+            }
+        };
+
+        let first = MutableItem::new(&keypair, Vec::new(), 1, b"1:a".to_vec());
+        let target = first.target();
+        assert_eq!(None, put(first));
+        let stale = MutableItem::new(&keypair, Vec::new(), 0, b"1:b".to_vec());
+        assert_eq!(Some(SEQUENCE_TOO_OLD), put(stale));
+        let second = MutableItem::new(&keypair, Vec::new(), 2, b"1:c".to_vec());
+        assert_eq!(None, put(second));
+
+        let data = svc.stored_data();
+        match data[&target] {
+            Item::Mutable(ref item) => assert_eq!(2, item.seq),
+            _ => panic!("wrong item"),
+        }
+    }
+}
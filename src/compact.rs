@@ -0,0 +1,228 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compact binary encoding of nodes, as in BitTorrent DHT.
+//!
+//! A node is its ID bytes followed by the IP address and the port in
+//! network byte order: 26 bytes for IPv4 and 38 bytes for IPv6 with
+//! 20 bytes IDs. Lists of nodes are such entries concatenated, with IPv4
+//! and IPv6 nodes packed separately (`nodes` and `nodes6` in BEP 32).
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use super::{GenericId, Node};
+
+/// ID with a binary representation of fixed size.
+pub trait CompactId: GenericId {
+    /// Append ID bytes to the buffer.
+    fn write_bytes(&self, buf: &mut Vec<u8>);
+    /// Make ID from bytes, `None` if their number is wrong.
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+impl CompactId for u64 {
+    fn write_bytes(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+    fn from_bytes(bytes: &[u8]) -> Option<u64> {
+        if bytes.len() != 8 {
+            return None;
+        }
+        let mut array = [0u8; 8];
+        array.copy_from_slice(bytes);
+        Some(u64::from_be_bytes(array))
+    }
+}
+
+impl CompactId for Vec<u8> {
+    fn write_bytes(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+    fn from_bytes(bytes: &[u8]) -> Option<Vec<u8>> {
+        Some(bytes.to_vec())
+    }
+}
+
+/// Nodes in compact form, split by address family.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackedNodes {
+    /// Concatenated IPv4 nodes.
+    pub ipv4: Vec<u8>,
+    /// Concatenated IPv6 nodes.
+    pub ipv6: Vec<u8>,
+}
+
+/// Size of a node in compact form.
+pub fn node_size(id_size: usize, ipv6: bool) -> usize {
+    id_size + if ipv6 { 18 } else { 6 }
+}
+
+impl<TId> Node<TId, SocketAddr>
+where
+    TId: CompactId,
+{
+    /// Append the node in compact form to the buffer.
+    pub fn write_compact(&self, buf: &mut Vec<u8>) {
+        self.id.write_bytes(buf);
+        match self.address {
+            SocketAddr::V4(ref addr) => buf.extend_from_slice(&addr.ip().octets()),
+            SocketAddr::V6(ref addr) => buf.extend_from_slice(&addr.ip().octets()),
+        }
+        buf.extend_from_slice(&self.address.port().to_be_bytes());
+    }
+
+    /// Encode the node in compact form.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_compact(&mut buf);
+        buf
+    }
+
+    /// Decode a node in compact form with `id_size` bytes long ID.
+    ///
+    /// Address family is detected from the length of data.
+    pub fn from_compact(data: &[u8], id_size: usize) -> Option<Node<TId, SocketAddr>> {
+        if data.len() == node_size(id_size, false) {
+            decode_node(data, id_size, false)
+        } else if data.len() == node_size(id_size, true) {
+            decode_node(data, id_size, true)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pack nodes in compact form, IDs must be of the same size.
+pub fn pack_nodes<TId>(nodes: &[Node<TId, SocketAddr>]) -> PackedNodes
+where
+    TId: CompactId,
+{
+    let mut packed = PackedNodes::default();
+    for node in nodes {
+        if node.address.is_ipv4() {
+            node.write_compact(&mut packed.ipv4);
+        } else {
+            node.write_compact(&mut packed.ipv6);
+        }
+    }
+    packed
+}
+
+/// Unpack concatenated nodes of one address family.
+///
+/// Returns `None` if data is not a whole number of nodes.
+pub fn unpack_nodes<TId>(
+    data: &[u8],
+    id_size: usize,
+    ipv6: bool,
+) -> Option<Vec<Node<TId, SocketAddr>>>
+where
+    TId: CompactId,
+{
+    let size = node_size(id_size, ipv6);
+    if !data.len().is_multiple_of(size) {
+        return None;
+    }
+    data.chunks(size)
+        .map(|chunk| decode_node(chunk, id_size, ipv6))
+        .collect()
+}
+
+fn decode_node<TId>(data: &[u8], id_size: usize, ipv6: bool) -> Option<Node<TId, SocketAddr>>
+where
+    TId: CompactId,
+{
+    debug_assert_eq!(data.len(), node_size(id_size, ipv6));
+    let (id, rest) = data.split_at(id_size);
+    let (ip, port) = rest.split_at(rest.len() - 2);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let address = if ipv6 {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(ip);
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+    } else {
+        SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
+            port,
+        ))
+    };
+    Some(Node {
+        id: TId::from_bytes(id)?,
+        address,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::super::Node;
+    use super::{pack_nodes, unpack_nodes, CompactId};
+
+    fn node(id: u8, address: &str) -> Node<Vec<u8>, SocketAddr> {
+        Node {
+            id: vec![id; 20],
+            address: address.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_node_ipv4() {
+        let node = node(1, "1.2.3.4:6881");
+        let data = node.to_compact();
+        assert_eq!(26, data.len());
+        assert_eq!(&[1, 2, 3, 4, 0x1a, 0xe1], &data[20..]);
+        let decoded = Node::<Vec<u8>, SocketAddr>::from_compact(&data, 20).unwrap();
+        assert_eq!(node.id, decoded.id);
+        assert_eq!(node.address, decoded.address);
+    }
+
+    #[test]
+    fn test_node_ipv6() {
+        let node = node(1, "[2001:db8::1]:6881");
+        let data = node.to_compact();
+        assert_eq!(38, data.len());
+        let decoded = Node::<Vec<u8>, SocketAddr>::from_compact(&data, 20).unwrap();
+        assert_eq!(node.address, decoded.address);
+        assert!(Node::<Vec<u8>, SocketAddr>::from_compact(&data[1..], 20).is_none());
+    }
+
+    #[test]
+    fn test_u64_id() {
+        let node = Node {
+            id: 0x0102030405060708u64,
+            address: "1.2.3.4:6881".parse::<SocketAddr>().unwrap(),
+        };
+        let data = node.to_compact();
+        assert_eq!(&[1, 2, 3, 4, 5, 6, 7, 8], &data[..8]);
+        assert_eq!(node.id, Node::<u64, _>::from_compact(&data, 8).unwrap().id);
+        assert!(u64::from_bytes(&data[..7]).is_none());
+        assert!(Node::<u64, SocketAddr>::from_compact(&data[1..], 7).is_none());
+    }
+
+    #[test]
+    fn test_pack_unpack() {
+        let nodes = vec![
+            node(1, "1.2.3.4:6881"),
+            node(2, "[::1]:6881"),
+            node(3, "5.6.7.8:6882"),
+        ];
+        let packed = pack_nodes(&nodes);
+        assert_eq!(52, packed.ipv4.len());
+        assert_eq!(38, packed.ipv6.len());
+
+        let ipv4: Vec<Node<Vec<u8>, SocketAddr>> = unpack_nodes(&packed.ipv4, 20, false).unwrap();
+        assert_eq!(vec![1, 3], ipv4.iter().map(|n| n.id[0]).collect::<Vec<_>>());
+        assert_eq!(nodes[2].address, ipv4[1].address);
+        let ipv6: Vec<Node<Vec<u8>, SocketAddr>> = unpack_nodes(&packed.ipv6, 20, true).unwrap();
+        assert_eq!(nodes[1].address, ipv6[0].address);
+
+        assert!(unpack_nodes::<Vec<u8>>(&packed.ipv4[1..], 20, false).is_none());
+        assert!(unpack_nodes::<Vec<u8>>(&[], 20, false).unwrap().is_empty());
+    }
+}
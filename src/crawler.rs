@@ -0,0 +1,544 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Crawler enumerating reachable nodes, for measuring the network.
+//!
+//! The crawler looks up random targets in the range of every bucket of a
+//! node table with `GenericAPI::find_node` and collects the nodes found,
+//! deduplicated by ID and address. Every node found is then pinged once,
+//! measuring its RTT and whether it answers. Lookups alternate between two
+//! rounds, the network size is estimated by comparing nodes seen in both
+//! rounds (capture-recapture). `Crawler::churn` compares the answering
+//! nodes of two crawls.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use super::{GenericAPI, GenericId, GenericNodeTable, Node};
+
+static DEFAULT_TARGETS_PER_BUCKET: usize = 4;
+// Random IDs to try when looking for a target in a bucket range
+static MAX_TARGET_ATTEMPTS: usize = 1024;
+
+/// Upper bounds of RTT ranges in `CrawlReport::rtt_histogram`, in ms.
+pub static RTT_BOUNDS_MS: [u64; 6] = [10, 50, 100, 500, 1000, 5000];
+
+/// Summary of a crawl.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrawlReport {
+    /// Number of unique nodes found.
+    pub nodes: usize,
+    /// Estimated number of nodes in the network, at least `nodes`.
+    pub estimate: usize,
+    /// Number of lookups issued.
+    pub lookups: usize,
+    /// Number of nodes pinged.
+    pub queries: usize,
+    /// Fraction of pinged nodes that answered.
+    pub response_rate: f64,
+    /// Nodes ignored for reusing the ID or the address of a known node.
+    pub duplicates: usize,
+    /// Number of nodes found per bucket of the node table.
+    pub id_distribution: Vec<usize>,
+    /// Number of answering nodes per RTT range, see `RTT_BOUNDS_MS`.
+    ///
+    /// The last item counts nodes slower than all bounds.
+    pub rtt_histogram: Vec<usize>,
+}
+
+/// Nodes that appeared or disappeared between two crawls.
+#[derive(Clone, Debug)]
+pub struct Churn<TId, TAddr> {
+    /// Nodes answering now, but not in the earlier crawl.
+    pub appeared: Vec<Node<TId, TAddr>>,
+    /// Nodes answering in the earlier crawl, but not now.
+    pub disappeared: Vec<Node<TId, TAddr>>,
+}
+
+/// Crawler, see module documentation.
+pub struct Crawler<TId, TAddr> {
+    targets_per_bucket: usize,
+    nodes: HashMap<TId, Found<TAddr>>,
+    addresses: HashMap<TAddr, TId>,
+    lookups: usize,
+    queries: usize,
+    responses: usize,
+    duplicates: usize,
+    rtt_histogram: Vec<usize>,
+}
+
+struct Found<TAddr> {
+    address: TAddr,
+    // Bit mask of rounds the node was found in
+    rounds: u8,
+    // Whether the node answered a ping, None if not pinged yet
+    answered: Option<bool>,
+}
+
+impl<TId, TAddr> Crawler<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Hash + Eq + Clone,
+{
+    /// Create a crawler with default settings.
+    pub fn new() -> Crawler<TId, TAddr> {
+        Crawler::new_with_details(DEFAULT_TARGETS_PER_BUCKET)
+    }
+
+    /// Create a crawler looking up `targets_per_bucket` IDs in every bucket.
+    pub fn new_with_details(targets_per_bucket: usize) -> Crawler<TId, TAddr> {
+        Crawler {
+            targets_per_bucket,
+            nodes: HashMap::new(),
+            addresses: HashMap::new(),
+            lookups: 0,
+            queries: 0,
+            responses: 0,
+            duplicates: 0,
+            rtt_histogram: vec![0; RTT_BOUNDS_MS.len() + 1],
+        }
+    }
+
+    /// Number of targets per bucket.
+    pub fn targets_per_bucket(&self) -> usize {
+        self.targets_per_bucket
+    }
+
+    /// Random targets in the range of every bucket of the table.
+    ///
+    /// Targets are found by trying random IDs, so buckets very close to
+    /// the table's own ID may get no targets.
+    pub fn targets<TNodeTable>(&self, table: &TNodeTable) -> Vec<TId>
+    where
+        TNodeTable: GenericNodeTable<TId, TAddr>,
+    {
+        let mut result = Vec::new();
+        for stats in table.bucket_stats() {
+            let mut wanted = self.targets_per_bucket;
+            for _ in 0..MAX_TARGET_ATTEMPTS {
+                if wanted == 0 {
+                    break;
+                }
+                let id = table.random_id();
+                if table.bucket_for(&id) == Some(stats.index) {
+                    result.push(id);
+                    wanted -= 1;
+                }
+            }
+            if wanted > 0 {
+                debug!("Not enough targets found for bucket {}", stats.index);
+            }
+        }
+        result
+    }
+
+    /// Look up every target, then ping the new nodes found.
+    ///
+    /// Can be called several times, results are accumulated.
+    pub fn crawl<TAPI>(&mut self, api: &mut TAPI, targets: &[TId])
+    where
+        TAPI: GenericAPI<TId, TAddr>,
+    {
+        for target in targets {
+            let round = self.lookups % 2;
+            self.lookups += 1;
+            let mut found = Vec::new();
+            api.find_node(target, |result| match result {
+                Ok(nodes) => found = nodes,
+                Err(error) => debug!("Lookup of {:?} failed: {:?}", target, error),
+            });
+            for node in found {
+                self.record_node(node, round);
+            }
+        }
+        self.ping_nodes(api);
+    }
+
+    /// Iterate over the nodes found.
+    pub fn nodes(&self) -> impl Iterator<Item = Node<TId, TAddr>> + '_ {
+        self.nodes.iter().map(|(id, found)| Node {
+            id: id.clone(),
+            address: found.address.clone(),
+        })
+    }
+
+    /// Iterate over the nodes that answered a ping.
+    pub fn answered_nodes(&self) -> impl Iterator<Item = Node<TId, TAddr>> + '_ {
+        self.nodes
+            .iter()
+            .filter(|(_, found)| found.answered == Some(true))
+            .map(|(id, found)| Node {
+                id: id.clone(),
+                address: found.address.clone(),
+            })
+    }
+
+    /// Compare answering nodes with those of an earlier crawl, by ID.
+    pub fn churn(&self, previous: &Crawler<TId, TAddr>) -> Churn<TId, TAddr> {
+        let answered = |crawler: &Crawler<TId, TAddr>, id: &TId| {
+            crawler
+                .nodes
+                .get(id)
+                .is_some_and(|found| found.answered == Some(true))
+        };
+        Churn {
+            appeared: self
+                .answered_nodes()
+                .filter(|n| !answered(previous, &n.id))
+                .collect(),
+            disappeared: previous
+                .answered_nodes()
+                .filter(|n| !answered(self, &n.id))
+                .collect(),
+        }
+    }
+
+    /// Summarize the crawl, with the ID distribution over the table's buckets.
+    pub fn report<TNodeTable>(&self, table: &TNodeTable) -> CrawlReport
+    where
+        TNodeTable: GenericNodeTable<TId, TAddr>,
+    {
+        let mut id_distribution = vec![0; table.bucket_stats().len()];
+        for bucket in self.nodes.keys().filter_map(|id| table.bucket_for(id)) {
+            if bucket >= id_distribution.len() {
+                id_distribution.resize(bucket + 1, 0);
+            }
+            id_distribution[bucket] += 1;
+        }
+
+        let round_count = |mask| {
+            self.nodes
+                .values()
+                .filter(|f| f.rounds & mask == mask)
+                .count()
+        };
+        let (first, second, both) = (round_count(1), round_count(2), round_count(3));
+        let estimate = (first * second).checked_div(both).unwrap_or(0);
+
+        CrawlReport {
+            nodes: self.nodes.len(),
+            estimate: estimate.max(self.nodes.len()),
+            lookups: self.lookups,
+            queries: self.queries,
+            response_rate: if self.queries > 0 {
+                self.responses as f64 / self.queries as f64
+            } else {
+                0.0
+            },
+            duplicates: self.duplicates,
+            id_distribution,
+            rtt_histogram: self.rtt_histogram.clone(),
+        }
+    }
+
+    fn ping_nodes<TAPI>(&mut self, api: &mut TAPI)
+    where
+        TAPI: GenericAPI<TId, TAddr>,
+    {
+        let pending: Vec<_> = self
+            .nodes()
+            .filter(|n| self.nodes[&n.id].answered.is_none())
+            .collect();
+        for node in pending {
+            self.queries += 1;
+            let started = Instant::now();
+            let mut rtt = None;
+            api.ping(&node, |_, ok| {
+                if ok {
+                    rtt = Some(started.elapsed());
+                }
+            });
+            if let Some(rtt) = rtt {
+                self.responses += 1;
+                self.record_rtt(rtt);
+            }
+            if let Some(found) = self.nodes.get_mut(&node.id) {
+                found.answered = Some(rtt.is_some());
+            }
+        }
+    }
+
+    fn record_rtt(&mut self, rtt: Duration) {
+        let ms = rtt.as_millis();
+        let index = RTT_BOUNDS_MS
+            .iter()
+            .position(|&bound| ms < u128::from(bound))
+            .unwrap_or(RTT_BOUNDS_MS.len());
+        self.rtt_histogram[index] += 1;
+    }
+
+    fn record_node(&mut self, node: Node<TId, TAddr>, round: usize) {
+        let mask = 1 << round;
+        if let Some(found) = self.nodes.get_mut(&node.id) {
+            if found.address == node.address {
+                found.rounds |= mask;
+            } else {
+                debug!("Node {:?} seen with a different address", node.id);
+                self.duplicates += 1;
+            }
+            return;
+        }
+        if self.addresses.contains_key(&node.address) {
+            debug!("Address of node {:?} already used by another ID", node.id);
+            self.duplicates += 1;
+            return;
+        }
+        self.addresses.insert(node.address.clone(), node.id.clone());
+        self.nodes.insert(
+            node.id,
+            Found {
+                address: node.address,
+                rounds: mask,
+                answered: None,
+            },
+        );
+    }
+}
+
+impl<TId, TAddr> Default for Crawler<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Hash + Eq + Clone,
+{
+    fn default() -> Crawler<TId, TAddr> {
+        Crawler::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::net::SocketAddr;
+
+    use super::super::protocol::{ResponseError, GENERIC_ERROR};
+    use super::super::service::FindResult;
+    use super::super::{GenericAPI, GenericNodeTable, KNodeTable, Node, Service};
+    use super::{Crawler, RTT_BOUNDS_MS};
+
+    type TestNode = Node<u64, SocketAddr>;
+    type TestService = Service<u64, SocketAddr, KNodeTable<u64, SocketAddr>, i32>;
+
+    fn node(id: u64) -> TestNode {
+        Node {
+            id,
+            address: SocketAddr::from(([127, 0, 0, 1], 8000 + id as u16)),
+        }
+    }
+
+    fn new_service(id: u64) -> TestService {
+        Service::new_with_id(KNodeTable::new_with_details(id, 4, 8), id)
+    }
+
+    /// Nodes answering lookups in process, nodes missing from `network`
+    /// never respond, nodes in `silent` do not answer pings.
+    struct NetworkAPI {
+        this: TestService,
+        network: HashMap<SocketAddr, TestService>,
+        silent: HashSet<SocketAddr>,
+    }
+
+    impl NetworkAPI {
+        /// Nodes 1 to `size` know their neighbours, our node 0 knows node 1.
+        fn new(size: u64) -> NetworkAPI {
+            let mut this = new_service(0);
+            this.node_table_mut().update(&node(1));
+            let mut network = HashMap::new();
+            for id in 1..=size {
+                let mut service = new_service(id);
+                for other in &[id - 1, id + 1, (id * 7) % size + 1] {
+                    if *other != id && *other <= size {
+                        service.node_table_mut().update(&node(*other));
+                    }
+                }
+                network.insert(node(id).address, service);
+            }
+            NetworkAPI {
+                this,
+                network,
+                silent: HashSet::new(),
+            }
+        }
+    }
+
+    impl GenericAPI<u64, SocketAddr> for NetworkAPI {
+        type TValue = i32;
+        fn ping<F>(&mut self, node: &TestNode, callback: F)
+        where
+            F: FnOnce(&TestNode, bool),
+        {
+            let answers =
+                self.network.contains_key(&node.address) && !self.silent.contains(&node.address);
+            callback(node, answers);
+        }
+        fn find_node<F>(&mut self, id: &u64, callback: F)
+        where
+            F: FnOnce(Result<Vec<TestNode>, ResponseError>),
+        {
+            let network = &mut self.network;
+            let result = self
+                .this
+                .lookup(id, |n, id| match network.get_mut(&n.address) {
+                    // KNodeTable does not look up its own ID
+                    Some(_) if n.id == *id => FindResult::ClosestNodes(vec![]),
+                    Some(service) => {
+                        FindResult::ClosestNodes(service.handler_mut().on_find_node(&node(0), id))
+                    }
+                    None => FindResult::Nothing,
+                });
+            match result {
+                FindResult::ClosestNodes(ref nodes) if !nodes.is_empty() => {
+                    callback(Ok(nodes.clone()))
+                }
+                _ => callback(Err(ResponseError::new(GENERIC_ERROR, "no nodes"))),
+            }
+        }
+        fn find_value<F>(&mut self, _id: &u64, callback: F)
+        where
+            F: FnOnce(Result<(Option<i32>, Vec<TestNode>), ResponseError>),
+        {
+            callback(Ok((None, vec![])));
+        }
+        fn store(&mut self, _node: &TestNode, _id: &u64, _value: i32) {}
+        fn add_provider(&mut self, _node: &TestNode, _id: &u64) {}
+        fn get_providers<F>(&mut self, _id: &u64, callback: F)
+        where
+            F: FnOnce(Result<(Vec<TestNode>, Vec<TestNode>), ResponseError>),
+        {
+            callback(Ok((vec![], vec![])));
+        }
+    }
+
+    #[test]
+    fn test_targets() {
+        let api = NetworkAPI::new(10);
+        let crawler = Crawler::new_with_details(2);
+        let table = api.this.node_table();
+        let targets = crawler.targets(&*table);
+        // Buckets closest to our ID are too small to reliably hit
+        assert!(targets.len() >= 12);
+        for bucket in 2..8 {
+            let count = targets
+                .iter()
+                .filter(|t| table.bucket_for(t) == Some(bucket))
+                .count();
+            assert_eq!(2, count);
+        }
+    }
+
+    #[test]
+    fn test_crawl() {
+        let mut api = NetworkAPI::new(40);
+        let mut crawler = Crawler::new();
+        let targets = crawler.targets(&*api.this.node_table());
+        crawler.crawl(&mut api, &targets);
+
+        let table = api.this.node_table();
+        let report = crawler.report(&*table);
+        assert_eq!(40, report.nodes);
+        assert!(report.estimate >= 40);
+        assert_eq!(targets.len(), report.lookups);
+        assert_eq!(40, report.queries);
+        assert_eq!(1.0, report.response_rate);
+        assert_eq!(0, report.duplicates);
+        assert_eq!(40, report.id_distribution.iter().sum::<usize>());
+        assert_eq!(RTT_BOUNDS_MS.len() + 1, report.rtt_histogram.len());
+        assert_eq!(40, report.rtt_histogram.iter().sum::<usize>());
+
+        let mut ids: Vec<_> = crawler.nodes().map(|n| n.id).collect();
+        ids.sort();
+        assert_eq!((1..=40).collect::<Vec<_>>(), ids);
+    }
+
+    #[test]
+    fn test_crawl_failures() {
+        let mut api = NetworkAPI::new(10);
+        api.network.clear();
+        let mut crawler = Crawler::new();
+        crawler.crawl(&mut api, &[5, 6]);
+        let report = crawler.report(&*api.this.node_table());
+        assert_eq!(0, report.nodes);
+        assert_eq!(2, report.lookups);
+        assert_eq!(0, report.queries);
+        assert_eq!(0.0, report.response_rate);
+        assert_eq!(0, report.rtt_histogram.iter().sum::<usize>());
+    }
+
+    #[test]
+    fn test_crawl_silent_nodes() {
+        let mut api = NetworkAPI::new(20);
+        api.silent.insert(node(5).address);
+        api.silent.insert(node(6).address);
+        let mut crawler = Crawler::new();
+        let targets = crawler.targets(&*api.this.node_table());
+        crawler.crawl(&mut api, &targets);
+        // Pinged nodes are not pinged again
+        crawler.crawl(&mut api, &targets);
+
+        let report = crawler.report(&*api.this.node_table());
+        assert_eq!(20, report.nodes);
+        assert_eq!(20, report.queries);
+        assert_eq!(0.9, report.response_rate);
+        assert_eq!(18, report.rtt_histogram.iter().sum::<usize>());
+        assert_eq!(18, crawler.answered_nodes().count());
+    }
+
+    #[test]
+    fn test_churn() {
+        let mut api = NetworkAPI::new(20);
+        let mut previous = Crawler::new();
+        let targets = previous.targets(&*api.this.node_table());
+        previous.crawl(&mut api, &targets);
+
+        api.silent.insert(node(5).address);
+        let mut joined = new_service(21);
+        joined.node_table_mut().update(&node(20));
+        api.network.insert(node(21).address, joined);
+        api.network
+            .get_mut(&node(20).address)
+            .unwrap()
+            .node_table_mut()
+            .update(&node(21));
+        let mut crawler = Crawler::new();
+        crawler.crawl(&mut api, &targets);
+
+        let churn = crawler.churn(&previous);
+        let ids = |nodes: &[TestNode]| nodes.iter().map(|n| n.id).collect::<Vec<_>>();
+        assert_eq!(vec![21], ids(&churn.appeared));
+        assert_eq!(vec![5], ids(&churn.disappeared));
+        let unchanged = previous.churn(&previous);
+        assert!(unchanged.appeared.is_empty());
+        assert!(unchanged.disappeared.is_empty());
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut crawler = Crawler::new();
+        crawler.record_node(node(1), 0);
+        crawler.record_node(node(1), 1);
+        // Same address, different ID
+        crawler.record_node(
+            Node {
+                id: 2,
+                address: node(1).address,
+            },
+            0,
+        );
+        // Same ID, different address
+        crawler.record_node(
+            Node {
+                id: 1,
+                address: node(3).address,
+            },
+            1,
+        );
+        assert_eq!(1, crawler.nodes().count());
+        assert_eq!(2, crawler.duplicates);
+        assert_eq!(3, crawler.nodes[&1].rounds);
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Node table keeping IPv4 and IPv6 nodes apart.
+
+use std::collections::BTreeMap;
+use std::marker;
+use std::net::SocketAddr;
+
+use super::protocol::Version;
+use super::{BucketStats, GenericId, GenericNodeTable, Node, SizeEstimate, UpdateResult};
+
+/// Node table with separate tables for IPv4 and IPv6 nodes (BEP 32).
+///
+/// Nodes of one family do not compete with nodes of the other for space
+/// in buckets. A node with both an IPv4 and an IPv6 address is kept in both
+/// tables, operations taking only an ID apply to both of them.
+pub struct DualStackTable<TId, TNodeTable> {
+    _phantom: marker::PhantomData<TId>,
+    ipv4: TNodeTable,
+    ipv6: TNodeTable,
+}
+
+impl<TId, TNodeTable> DualStackTable<TId, TNodeTable>
+where
+    TId: GenericId,
+    TNodeTable: GenericNodeTable<TId, SocketAddr>,
+{
+    /// Create a table from tables for IPv4 and IPv6 nodes.
+    pub fn new(ipv4: TNodeTable, ipv6: TNodeTable) -> DualStackTable<TId, TNodeTable> {
+        DualStackTable {
+            _phantom: marker::PhantomData,
+            ipv4,
+            ipv6,
+        }
+    }
+
+    /// Table with IPv4 nodes.
+    pub fn ipv4(&self) -> &TNodeTable {
+        &self.ipv4
+    }
+    /// Table with IPv6 nodes.
+    pub fn ipv6(&self) -> &TNodeTable {
+        &self.ipv6
+    }
+
+    fn table_for_mut(&mut self, address: &SocketAddr) -> &mut TNodeTable {
+        if address.is_ipv4() {
+            &mut self.ipv4
+        } else {
+            &mut self.ipv6
+        }
+    }
+}
+
+impl<TId, TNodeTable> GenericNodeTable<TId, SocketAddr> for DualStackTable<TId, TNodeTable>
+where
+    TId: GenericId,
+    TNodeTable: GenericNodeTable<TId, SocketAddr>,
+{
+    fn random_id(&self) -> TId {
+        self.ipv4.random_id()
+    }
+
+    fn update(&mut self, node: &Node<TId, SocketAddr>) -> UpdateResult {
+        self.table_for_mut(&node.address).update(node)
+    }
+
+    fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, SocketAddr>> {
+        let mut result = self.ipv4.find(id, count);
+        result.extend(self.ipv6.find(id, count));
+        result.sort_by_key(|n| n.id.bitxor(id));
+        result.truncate(count);
+        result
+    }
+
+    fn find_filtered(
+        &self,
+        id: &TId,
+        count: usize,
+        filter: &dyn Fn(&Node<TId, SocketAddr>) -> bool,
+    ) -> Vec<Node<TId, SocketAddr>> {
+        let mut result = self.ipv4.find_filtered(id, count, filter);
+        result.extend(self.ipv6.find_filtered(id, count, filter));
+        result.sort_by_key(|n| n.id.bitxor(id));
+        result.truncate(count);
+        result
+    }
+
+    fn pop_oldest(&mut self) -> Vec<Node<TId, SocketAddr>> {
+        let mut result = self.ipv4.pop_oldest();
+        result.extend(self.ipv6.pop_oldest());
+        result
+    }
+
+    /// Remove the node from both tables, returns the IPv4 entry if any.
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, SocketAddr>> {
+        let ipv4 = self.ipv4.remove(id);
+        let ipv6 = self.ipv6.remove(id);
+        ipv4.or(ipv6)
+    }
+
+    fn remove_by_address(&mut self, address: &SocketAddr) -> Vec<Node<TId, SocketAddr>> {
+        self.table_for_mut(address).remove_by_address(address)
+    }
+
+    fn len(&self) -> usize {
+        self.ipv4.len() + self.ipv6.len()
+    }
+
+    /// Get the IPv4 entry of the node if any, the IPv6 one otherwise.
+    fn get(&self, id: &TId) -> Option<&Node<TId, SocketAddr>> {
+        self.ipv4.get(id).or_else(|| self.ipv6.get(id))
+    }
+
+    fn nodes<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Node<TId, SocketAddr>> + 'a>
+    where
+        TId: 'a,
+    {
+        Box::new(self.ipv4.nodes().chain(self.ipv6.nodes()))
+    }
+
+    /// Bucket in the table of the node's family, IPv4 for unknown IDs.
+    fn bucket_for(&self, id: &TId) -> Option<usize> {
+        if self.ipv6.contains(id) {
+            self.ipv6.bucket_for(id)
+        } else {
+            self.ipv4.bucket_for(id)
+        }
+    }
+
+    /// Statistics of buckets with the same index summed over both tables.
+    fn bucket_stats(&self) -> Vec<BucketStats> {
+        let mut merged = BTreeMap::new();
+        for stats in self
+            .ipv4
+            .bucket_stats()
+            .into_iter()
+            .chain(self.ipv6.bucket_stats())
+        {
+            let entry = merged.entry(stats.index).or_insert(BucketStats {
+                index: stats.index,
+                len: 0,
+                capacity: 0,
+            });
+            entry.len += stats.len;
+            entry.capacity += stats.capacity;
+        }
+        merged.into_values().collect()
+    }
+
+    fn set_version(&mut self, id: &TId, version: Version) {
+        if self.ipv6.contains(id) {
+            self.ipv6.set_version(id, version.clone());
+        }
+        self.ipv4.set_version(id, version);
+    }
+
+    fn version(&self, id: &TId) -> Option<&Version> {
+        self.ipv4.version(id).or_else(|| self.ipv6.version(id))
+    }
+
+    fn record_lookup(&mut self, target: &TId, closest: &[Node<TId, SocketAddr>]) {
+        let (ipv4, ipv6): (Vec<_>, Vec<_>) =
+            closest.iter().cloned().partition(|n| n.address.is_ipv4());
+        if !ipv4.is_empty() {
+            self.ipv4.record_lookup(target, &ipv4);
+        }
+        if !ipv6.is_empty() {
+            self.ipv6.record_lookup(target, &ipv6);
+        }
+    }
+
+    /// Estimate of the family with more samples, the networks are separate.
+    fn estimate_size(&self) -> Option<SizeEstimate> {
+        match (self.ipv4.estimate_size(), self.ipv6.estimate_size()) {
+            (Some(v4), Some(v6)) if v6.samples > v4.samples => Some(v6),
+            (None, v6) => v6,
+            (v4, _) => v4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::super::protocol::Version;
+    use super::super::{GenericNodeTable, KNodeTable, Node};
+    use super::DualStackTable;
+
+    fn node(id: u64, address: &str) -> Node<u64, SocketAddr> {
+        Node {
+            id,
+            address: address.parse().unwrap(),
+        }
+    }
+
+    fn new_table() -> DualStackTable<u64, KNodeTable<u64, SocketAddr>> {
+        DualStackTable::new(
+            KNodeTable::new_with_details(0, 1, 8),
+            KNodeTable::new_with_details(0, 1, 8),
+        )
+    }
+
+    #[test]
+    fn test_families_kept_apart() {
+        let mut table = new_table();
+        let v4 = node(1, "127.0.0.1:8008");
+        let v6 = node(2, "[::1]:8008");
+        assert!(table.update(&v4).is_stored());
+        assert!(table.update(&v6).is_stored());
+        // Buckets are per family, so both fit with bucket size 1
+        assert!(table.update(&node(3, "127.0.0.2:8008")).is_stored());
+        assert_eq!(3, table.len());
+        assert_eq!(2, table.ipv4().len());
+        assert_eq!(1, table.ipv6().len());
+
+        let stats = table.bucket_stats();
+        assert_eq!(8, stats.len());
+        assert_eq!(2, stats[1].len);
+        assert_eq!(2, stats[1].capacity);
+
+        assert_eq!(2, table.find(&1, 2).len());
+        let v6_only = table.find_filtered(&1, 8, &|n| n.address.is_ipv6());
+        assert_eq!(vec![2], v6_only.iter().map(|n| n.id).collect::<Vec<_>>());
+
+        assert_eq!(v6.address, table.remove(&2).unwrap().address);
+        assert_eq!(1, table.remove_by_address(&v4.address).len());
+        assert_eq!(1, table.len());
+    }
+
+    #[test]
+    fn test_dual_homed_node() {
+        let mut table = new_table();
+        assert!(table.update(&node(1, "127.0.0.1:8008")).is_stored());
+        assert!(table.update(&node(1, "[::1]:8008")).is_stored());
+        assert_eq!(2, table.len());
+
+        let version = Version::current();
+        table.set_version(&1, version.clone());
+        assert_eq!(Some(&version), table.ipv4().version(&1));
+        assert_eq!(Some(&version), table.ipv6().version(&1));
+
+        assert!(table.remove(&1).unwrap().address.is_ipv4());
+        assert!(table.is_empty());
+        assert!(!table.ipv6().contains(&1));
+    }
+
+    #[test]
+    fn test_bucket_for_family() {
+        // IPv6 table has a longer hash size, its nodes use its buckets
+        let mut table = DualStackTable::new(
+            KNodeTable::new_with_details(0, 1, 8),
+            KNodeTable::new_with_details(0, 1, 16),
+        );
+        assert!(table.update(&node(0x1000, "[::1]:8008")).is_stored());
+        assert_eq!(Some(12), table.bucket_for(&0x1000));
+        assert_eq!(None, table.bucket_for(&0x2000));
+        assert_eq!(Some(1), table.bucket_for(&2));
+    }
+}
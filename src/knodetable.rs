@@ -15,16 +15,23 @@
 //! using `pop_oldest` call.
 
 use std::cmp;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
+use std::net;
 
+use super::protocol::Version;
+use super::BucketStats;
 use super::GenericId;
 use super::GenericNodeTable;
 use super::Node;
+use super::SizeEstimate;
+use super::UpdateResult;
 
 // TODO(divius): make public?
 static BUCKET_SIZE: usize = 32;
 static DEFAULT_HASH_SIZE: usize = 64;
+// Number of recent lookups used for size estimation
+static MAX_LOOKUP_ESTIMATES: usize = 16;
 
 /// Kademlia node table.
 ///
@@ -39,14 +46,69 @@ pub struct KNodeTable<TId, TAddr> {
     hash_size: usize,
     // TODO(divius): convert to more appropriate data structure
     buckets: Vec<KBucket<TId, TAddr>>,
+    address_policy: Option<AddressPolicy<TAddr>>,
+    lookup_estimates: VecDeque<SizeEstimate>,
+}
+
+/// Limits on how many nodes may share an IP address or a network prefix.
+///
+/// Prefix is /24 for IPv4 and /64 for IPv6. `None` means no limit.
+/// Protects from a single host filling buckets with many IDs on different
+/// ports (Sybil and eclipse attacks).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressLimits {
+    /// Maximum number of nodes with the same IP in one bucket.
+    pub per_bucket_ip: Option<usize>,
+    /// Maximum number of nodes with the same prefix in one bucket.
+    pub per_bucket_prefix: Option<usize>,
+    /// Maximum number of nodes with the same IP in the whole table.
+    pub per_table_ip: Option<usize>,
+    /// Maximum number of nodes with the same prefix in the whole table.
+    pub per_table_prefix: Option<usize>,
+}
+
+struct AddressPolicy<TAddr> {
+    limits: AddressLimits,
+    ip: fn(&TAddr) -> net::IpAddr,
+    // Kept up to date on every insert and removal
+    table_counts: AddressCounts,
+    bucket_counts: Vec<AddressCounts>,
+}
+
+/// Number of nodes per IP address and per network prefix.
+#[derive(Default)]
+struct AddressCounts {
+    ips: HashMap<net::IpAddr, usize>,
+    prefixes: HashMap<net::IpAddr, usize>,
 }
 
 /// K-bucket - structure for keeping last nodes in Kademlia.
+///
+/// Nodes are kept in a doubly linked list threaded through a slab, ordered
+/// from the least recently seen to the most recently seen, with an index by
+/// ID. This makes both lookup and promotion of a known node O(1).
 pub struct KBucket<TId, TAddr> {
-    data: VecDeque<Node<TId, TAddr>>,
+    slots: Vec<Slot<TId, TAddr>>,
+    index: HashMap<TId, usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
     size: usize,
 }
 
+struct Slot<TId, TAddr> {
+    node: Node<TId, TAddr>,
+    version: Option<Version>,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+/// Iterator over nodes in a k-bucket, from the least recently seen.
+pub struct KBucketIter<'a, TId: 'a, TAddr: 'a> {
+    bucket: &'a KBucket<TId, TAddr>,
+    current: Option<usize>,
+    remaining: usize,
+}
+
 impl<TId, TAddr> KNodeTable<TId, TAddr>
 where
     TId: GenericId,
@@ -65,9 +127,11 @@ where
         hash_size: usize,
     ) -> KNodeTable<TId, TAddr> {
         KNodeTable {
-            this_id: this_id,
-            hash_size: hash_size,
+            this_id,
+            hash_size,
             buckets: (0..hash_size).map(|_| KBucket::new(bucket_size)).collect(),
+            address_policy: None,
+            lookup_estimates: VecDeque::new(),
         }
     }
 
@@ -80,22 +144,158 @@ where
         id1.bitxor(id2)
     }
 
-    fn bucket_number(&self, id: &TId) -> usize {
-        let diff = KNodeTable::<TId, TAddr>::distance(&self.this_id, id);
-        debug_assert!(!diff.is_zero());
-        let res = diff.bits() - 1;
-        if res >= self.hash_size {
-            panic!(format!(
-                "Distance between IDs {:?} and {:?} is {:?}, which is \
-                 greater than the hash size ({:?})",
-                id, self.this_id, res, self.hash_size
-            ));
-        }
-        debug!(
-            "ID {:?} relative to own ID {:?} falls into bucket {:?}",
-            id, self.this_id, res
-        );
-        res
+    // Nodes in buckets below the first full one are assumed to be all
+    // nodes at that distance, bucket N covers 2^N IDs. Without a full
+    // bucket the table may hold the whole network, so there is nothing
+    // to extrapolate from.
+    fn density_estimate(&self) -> Option<SizeEstimate> {
+        let covered = self
+            .buckets
+            .iter()
+            .position(|bucket| bucket.len() >= bucket.size())?;
+        let known: usize = self.buckets[..covered].iter().map(|b| b.len()).sum();
+        if known == 0 {
+            return None;
+        }
+        let scale = 2f64.powi((self.hash_size - covered) as i32);
+        Some(SizeEstimate::new(known as f64 * scale, known, known))
+    }
+
+    // Own ID, IDs of a different length and IDs further than the hash
+    // size allows have no bucket
+    fn bucket_number(&self, id: &TId) -> Option<usize> {
+        if !id.same_length(&self.this_id) {
+            return None;
+        }
+        let bits = KNodeTable::<TId, TAddr>::distance(&self.this_id, id).bits();
+        if bits == 0 || bits > self.hash_size {
+            return None;
+        }
+        Some(bits - 1)
+    }
+
+    fn allowed_by_policy(&self, node: &Node<TId, TAddr>, bucket: usize) -> bool {
+        let policy = match self.address_policy {
+            Some(ref policy) => policy,
+            None => return true,
+        };
+        let limits = &policy.limits;
+        let ip = (policy.ip)(&node.address);
+        let prefix = network_prefix(&ip);
+        let in_bucket = &policy.bucket_counts[bucket];
+        let counts = [
+            in_bucket.ip(&ip),
+            in_bucket.prefix(&prefix),
+            policy.table_counts.ip(&ip),
+            policy.table_counts.prefix(&prefix),
+        ];
+
+        let checks = [
+            limits.per_bucket_ip,
+            limits.per_bucket_prefix,
+            limits.per_table_ip,
+            limits.per_table_prefix,
+        ];
+        let allowed = checks
+            .iter()
+            .zip(counts.iter())
+            .all(|(limit, count)| limit.is_none_or(|limit| *count < limit));
+        if !allowed {
+            debug!(
+                "Not adding node {:?} - too many nodes from {:?} already",
+                node, ip
+            );
+        }
+        allowed
+    }
+
+    fn count_added(&mut self, bucket: usize, address: &TAddr) {
+        if let Some(ref mut policy) = self.address_policy {
+            let ip = (policy.ip)(address);
+            policy.table_counts.add(ip);
+            policy.bucket_counts[bucket].add(ip);
+        }
+    }
+
+    fn count_removed(&mut self, bucket: usize, address: &TAddr) {
+        if let Some(ref mut policy) = self.address_policy {
+            let ip = (policy.ip)(address);
+            policy.table_counts.remove(ip);
+            policy.bucket_counts[bucket].remove(ip);
+        }
+    }
+}
+
+impl<TId> KNodeTable<TId, net::SocketAddr>
+where
+    TId: GenericId,
+{
+    /// Limit how many nodes may share an IP address or a network prefix.
+    ///
+    /// Nodes violating the limits are reported as `UpdateResult::Rejected`.
+    /// Nodes already in the table are not affected.
+    pub fn set_address_limits(&mut self, limits: AddressLimits) {
+        self.address_policy = Some(AddressPolicy {
+            limits,
+            ip: net::SocketAddr::ip,
+            table_counts: AddressCounts::default(),
+            bucket_counts: self
+                .buckets
+                .iter()
+                .map(|_| AddressCounts::default())
+                .collect(),
+        });
+        let known: Vec<_> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, b)| b.iter().map(move |n| (idx, n.address)))
+            .collect();
+        for (bucket, address) in known {
+            self.count_added(bucket, &address);
+        }
+    }
+}
+
+impl AddressCounts {
+    fn ip(&self, ip: &net::IpAddr) -> usize {
+        self.ips.get(ip).cloned().unwrap_or(0)
+    }
+
+    fn prefix(&self, prefix: &net::IpAddr) -> usize {
+        self.prefixes.get(prefix).cloned().unwrap_or(0)
+    }
+
+    fn add(&mut self, ip: net::IpAddr) {
+        *self.ips.entry(ip).or_insert(0) += 1;
+        *self.prefixes.entry(network_prefix(&ip)).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, ip: net::IpAddr) {
+        decrement(&mut self.ips, ip);
+        decrement(&mut self.prefixes, network_prefix(&ip));
+    }
+}
+
+fn decrement(counts: &mut HashMap<net::IpAddr, usize>, key: net::IpAddr) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+fn network_prefix(ip: &net::IpAddr) -> net::IpAddr {
+    match *ip {
+        net::IpAddr::V4(ip) => {
+            let o = ip.octets();
+            net::IpAddr::V4(net::Ipv4Addr::new(o[0], o[1], o[2], 0))
+        }
+        net::IpAddr::V6(ip) => {
+            let s = ip.segments();
+            net::IpAddr::V6(net::Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
     }
 }
 
@@ -108,10 +308,32 @@ where
         TId::gen(self.hash_size)
     }
 
-    fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
-        assert!(node.id != self.this_id);
-        let bucket = self.bucket_number(&node.id);
-        self.buckets[bucket].update(node)
+    fn update(&mut self, node: &Node<TId, TAddr>) -> UpdateResult {
+        let bucket = match self.bucket_number(&node.id) {
+            Some(bucket) => bucket,
+            None => {
+                debug!("Not adding node {:?} - ID out of range", node);
+                return UpdateResult::Rejected;
+            }
+        };
+        if self.address_policy.is_none() {
+            return self.buckets[bucket].update(node);
+        }
+        let known = self.buckets[bucket]
+            .get(&node.id)
+            .map(|n| n.address.clone());
+        // Nodes already in the bucket have been counted
+        if known.is_none() && !self.allowed_by_policy(node, bucket) {
+            return UpdateResult::Rejected;
+        }
+        let result = self.buckets[bucket].update(node);
+        if let Some(previous) = known {
+            self.count_removed(bucket, &previous);
+        }
+        if result != UpdateResult::Full {
+            self.count_added(bucket, &node.address);
+        }
+        result
     }
 
     fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
@@ -121,8 +343,8 @@ where
         let mut data_copy: Vec<_> = self
             .buckets
             .iter()
-            .flat_map(|b| &b.data)
-            .map(|n| n.clone())
+            .flat_map(|b| b.iter())
+            .cloned()
             .collect();
         data_copy.sort_by_key(|n| KNodeTable::<TId, TAddr>::distance(id, &n.id));
         data_copy[0..cmp::min(count, data_copy.len())].to_vec()
@@ -131,12 +353,107 @@ where
     fn pop_oldest(&mut self) -> Vec<Node<TId, TAddr>> {
         // For every full k-bucket, pop the last.
         // TODO(divius): TTL expiration?
-        self.buckets
+        let popped: Vec<_> = self
+            .buckets
             .iter_mut()
-            .filter(|b| !b.data.is_empty() && b.size == b.data.len())
-            .map(|b| b.data.pop_front().unwrap())
+            .enumerate()
+            .filter(|(_, b)| !b.is_empty() && b.size == b.len())
+            .map(|(idx, b)| (idx, b.pop_front().unwrap()))
+            .collect();
+        for &(bucket, ref node) in &popped {
+            self.count_removed(bucket, &node.address);
+        }
+        popped.into_iter().map(|(_, node)| node).collect()
+    }
+
+    fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        let bucket = self.bucket_for(id)?;
+        let node = self.buckets[bucket].remove(id)?;
+        self.count_removed(bucket, &node.address);
+        Some(node)
+    }
+
+    fn remove_by_address(&mut self, address: &TAddr) -> Vec<Node<TId, TAddr>>
+    where
+        TAddr: PartialEq,
+    {
+        let mut removed = Vec::new();
+        for bucket in 0..self.buckets.len() {
+            for node in self.buckets[bucket].remove_by_address(address) {
+                self.count_removed(bucket, &node.address);
+                removed.push(node);
+            }
+        }
+        removed
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    fn get(&self, id: &TId) -> Option<&Node<TId, TAddr>> {
+        self.bucket_for(id).and_then(|b| self.buckets[b].get(id))
+    }
+
+    fn nodes<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Node<TId, TAddr>> + 'a>
+    where
+        TId: 'a,
+        TAddr: 'a,
+    {
+        Box::new(self.buckets.iter().flat_map(|b| b.iter()))
+    }
+
+    fn bucket_for(&self, id: &TId) -> Option<usize> {
+        self.bucket_number(id)
+    }
+
+    fn bucket_stats(&self) -> Vec<BucketStats> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(index, b)| BucketStats {
+                index,
+                len: b.len(),
+                capacity: b.size(),
+            })
             .collect()
     }
+
+    fn set_version(&mut self, id: &TId, version: Version) {
+        if let Some(bucket) = self.bucket_for(id) {
+            self.buckets[bucket].set_version(id, version);
+        }
+    }
+
+    fn version(&self, id: &TId) -> Option<&Version> {
+        self.bucket_for(id)
+            .and_then(|b| self.buckets[b].version(id))
+    }
+
+    /// The farthest of `closest` nodes is taken as the distance within
+    /// which that many nodes live. Only its bit size is known, so the
+    /// middle of the range is used.
+    fn record_lookup(&mut self, target: &TId, closest: &[Node<TId, TAddr>]) {
+        let bits = match closest.iter().map(|n| n.id.bitxor(target).bits()).max() {
+            Some(bits) if bits > 0 => bits,
+            _ => return,
+        };
+        let scale = 2f64.powf(self.hash_size as f64 - bits as f64 + 0.5);
+        let count = closest.len();
+        if self.lookup_estimates.len() >= MAX_LOOKUP_ESTIMATES {
+            self.lookup_estimates.pop_front();
+        }
+        self.lookup_estimates
+            .push_back(SizeEstimate::new(count as f64 * scale, count, 0));
+    }
+
+    /// Combines the density of IDs in the closest buckets with recent
+    /// lookup results.
+    fn estimate_size(&self) -> Option<SizeEstimate> {
+        let mut estimates: Vec<_> = self.lookup_estimates.iter().cloned().collect();
+        estimates.extend(self.density_estimate());
+        SizeEstimate::combine(&estimates, self.len())
+    }
 }
 
 impl<TId, TAddr> KBucket<TId, TAddr>
@@ -147,60 +464,204 @@ where
     pub fn new(k: usize) -> KBucket<TId, TAddr> {
         assert!(k > 0);
         KBucket {
-            data: VecDeque::new(),
+            slots: Vec::with_capacity(k),
+            index: HashMap::with_capacity(k),
+            head: None,
+            tail: None,
             size: k,
         }
     }
 
-    pub fn update(&mut self, node: &Node<TId, TAddr>) -> bool {
-        if self.data.iter().any(|x| x.id == node.id) {
-            self.update_position(node.clone());
+    pub fn update(&mut self, node: &Node<TId, TAddr>) -> UpdateResult {
+        if let Some(&idx) = self.index.get(&node.id) {
+            self.update_position(idx, node.clone());
             debug!("Promoted node {:?} to the top of kbucket", node);
-            true
-        } else if self.data.len() == self.size {
+            UpdateResult::Updated
+        } else if self.len() == self.size {
             debug!("Not adding new node {:?} to kbucket - no space left", node);
-            false
+            UpdateResult::Full
         } else {
-            self.data.push_back(node.clone());
+            self.push_back(node.clone());
             debug!("Added new node {:?} to kbucket", node);
-            true
+            UpdateResult::Added
         }
     }
 
     pub fn find(&self, id: &TId, count: usize) -> Vec<Node<TId, TAddr>> {
-        let mut data_copy: Vec<_> = self.data.iter().map(|n| n.clone()).collect();
+        let mut data_copy: Vec<_> = self.iter().cloned().collect();
         data_copy.sort_by_key(|n| KNodeTable::<TId, TAddr>::distance(id, &n.id));
         data_copy[0..cmp::min(count, data_copy.len())].to_vec()
     }
 
-    pub fn data(&self) -> &VecDeque<Node<TId, TAddr>> {
-        &self.data
+    /// Get node with given ID, if present.
+    pub fn get(&self, id: &TId) -> Option<&Node<TId, TAddr>> {
+        self.index.get(id).map(|&idx| &self.slots[idx].node)
+    }
+
+    /// Remember the version advertised by the node, returns false if absent.
+    pub fn set_version(&mut self, id: &TId, version: Version) -> bool {
+        match self.index.get(id) {
+            Some(&idx) => {
+                self.slots[idx].version = Some(version);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the version advertised by the node, if known.
+    pub fn version(&self, id: &TId) -> Option<&Version> {
+        self.index
+            .get(id)
+            .and_then(|&idx| self.slots[idx].version.as_ref())
+    }
+
+    /// Remove node with given ID, if present.
+    pub fn remove(&mut self, id: &TId) -> Option<Node<TId, TAddr>> {
+        let idx = *self.index.get(id)?;
+        debug!("Removing node with ID {:?} from kbucket", id);
+        Some(self.remove_slot(idx))
+    }
+
+    /// Remove all nodes with given address.
+    pub fn remove_by_address(&mut self, address: &TAddr) -> Vec<Node<TId, TAddr>>
+    where
+        TAddr: PartialEq,
+    {
+        let ids: Vec<TId> = self
+            .iter()
+            .filter(|n| n.address == *address)
+            .map(|n| n.id.clone())
+            .collect();
+        ids.iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    /// Nodes, from the least recently seen to the most recently seen.
+    ///
+    /// Same as `iter`.
+    pub fn data(&self) -> KBucketIter<'_, TId, TAddr> {
+        self.iter()
+    }
+    /// Iterate over nodes, from the least recently seen to the most recently seen.
+    pub fn iter(&self) -> KBucketIter<'_, TId, TAddr> {
+        KBucketIter {
+            bucket: self,
+            current: self.head,
+            remaining: self.len(),
+        }
     }
     pub fn size(&self) -> usize {
         self.size
     }
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
 
-    fn update_position(&mut self, node: Node<TId, TAddr>) {
-        // TODO(divius): 1. optimize, 2. make it less ugly
-        let mut new_data = VecDeque::with_capacity(self.data.len());
-        new_data.extend(
-            self.data
-                .iter()
-                .filter(|x| x.id != node.id)
-                .map(|x| x.clone()),
-        );
-        new_data.push_back(node.clone());
-        self.data = new_data;
+    fn update_position(&mut self, idx: usize, node: Node<TId, TAddr>) {
+        self.slots[idx].node = node;
+        if self.tail != Some(idx) {
+            self.unlink(idx);
+            self.link_back(idx);
+        }
+    }
+
+    fn push_back(&mut self, node: Node<TId, TAddr>) {
+        let idx = self.slots.len();
+        self.index.insert(node.id.clone(), idx);
+        self.slots.push(Slot {
+            node,
+            version: None,
+            prev: None,
+            next: None,
+        });
+        self.link_back(idx);
+    }
+
+    fn pop_front(&mut self) -> Option<Node<TId, TAddr>> {
+        self.head.map(|idx| self.remove_slot(idx))
+    }
+
+    fn remove_slot(&mut self, idx: usize) -> Node<TId, TAddr> {
+        self.unlink(idx);
+        let last = self.slots.len() - 1;
+        if idx != last {
+            // The last slot is about to move into idx, fix everything
+            // pointing at it.
+            let (prev, next) = (self.slots[last].prev, self.slots[last].next);
+            match prev {
+                Some(p) => self.slots[p].next = Some(idx),
+                None => self.head = Some(idx),
+            }
+            match next {
+                Some(n) => self.slots[n].prev = Some(idx),
+                None => self.tail = Some(idx),
+            }
+            *self.index.get_mut(&self.slots[last].node.id).unwrap() = idx;
+        }
+        let slot = self.slots.swap_remove(idx);
+        self.index.remove(&slot.node.id);
+        slot.node
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = (self.slots[idx].prev, self.slots[idx].next);
+        match prev {
+            Some(p) => self.slots[p].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(n) => self.slots[n].prev = prev,
+            None => self.tail = prev,
+        }
+        self.slots[idx].prev = None;
+        self.slots[idx].next = None;
+    }
+
+    fn link_back(&mut self, idx: usize) {
+        self.slots[idx].prev = self.tail;
+        self.slots[idx].next = None;
+        match self.tail {
+            Some(t) => self.slots[t].next = Some(idx),
+            None => self.head = Some(idx),
+        }
+        self.tail = Some(idx);
+    }
+}
+
+impl<'a, TId, TAddr> Iterator for KBucketIter<'a, TId, TAddr> {
+    type Item = &'a Node<TId, TAddr>;
+
+    fn next(&mut self) -> Option<&'a Node<TId, TAddr>> {
+        self.current.map(|idx| {
+            let slot = &self.bucket.slots[idx];
+            self.current = slot.next;
+            self.remaining -= 1;
+            &slot.node
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
+impl<'a, TId, TAddr> ExactSizeIterator for KBucketIter<'a, TId, TAddr> {}
+
 #[cfg(test)]
 mod test {
+    use std::collections::VecDeque;
     use std::net;
 
+    use super::super::protocol::Version;
+    use super::super::BucketStats;
     use super::super::GenericNodeTable;
     use super::super::Node;
+    use super::super::UpdateResult;
 
+    use super::AddressLimits;
     use super::KBucket;
     use super::KNodeTable;
     use super::DEFAULT_HASH_SIZE;
@@ -210,17 +671,16 @@ mod test {
     use super::super::base::GenericId;
 
     fn prepare(count: u8) -> KBucket<TestsIdType, net::SocketAddr> {
-        KBucket {
-            data: (0..count)
-                .map(|i| test::new_node(test::make_id(i)))
-                .collect(),
-            size: 3,
+        let mut b = KBucket::new(3);
+        for i in 0..count {
+            assert!(b.update(&test::new_node(test::make_id(i))).is_stored());
         }
+        b
     }
 
     fn assert_node_list_eq(
         expected: &[&Node<TestsIdType, net::SocketAddr>],
-        actual: &Vec<Node<TestsIdType, net::SocketAddr>>,
+        actual: &[Node<TestsIdType, net::SocketAddr>],
     ) {
         let act: Vec<TestsIdType> = actual.iter().map(|n| n.id.clone()).collect();
         let exp: Vec<TestsIdType> = expected.iter().map(|n| n.id.clone()).collect();
@@ -238,7 +698,7 @@ mod test {
         let n = KNodeTable::<u64, ()>::new(42);
         let id = 41;
         // 42 xor 41 == 3
-        assert_eq!(1, n.bucket_number(&id));
+        assert_eq!(Some(1), n.bucket_number(&id));
     }
 
     #[test]
@@ -256,7 +716,10 @@ mod test {
         lengths[0] = 1;
         lengths[1] = 2;
         assert_eq!(
-            n.buckets().iter().map(|b| b.data.len()).collect::<Vec<_>>(),
+            n.buckets()
+                .iter()
+                .map(|b| b.data().len())
+                .collect::<Vec<_>>(),
             lengths
         );
 
@@ -265,10 +728,13 @@ mod test {
         assert_eq!(test::make_id(41), nodes[0].id);
         lengths[1] = 1;
         assert_eq!(
-            n.buckets().iter().map(|b| b.data.len()).collect::<Vec<_>>(),
+            n.buckets()
+                .iter()
+                .map(|b| b.data().len())
+                .collect::<Vec<_>>(),
             lengths
         );
-        assert_eq!(test::make_id(40), n.buckets[1].data[0].id);
+        assert_eq!(test::make_id(40), n.buckets[1].data().next().unwrap().id);
     }
 
     #[test]
@@ -277,14 +743,15 @@ mod test {
             buckets: vec![prepare(1), prepare(3), prepare(1)],
             this_id: test::make_id(0),
             hash_size: DEFAULT_HASH_SIZE,
+            address_policy: None,
+            lookup_estimates: VecDeque::new(),
         };
         // 0 xor 3 = 3, 1 xor 3 = 2, 2 xor 3 = 1
         let id = test::make_id(3);
-        assert_node_list_eq(&[&n.buckets[1].data[2]], &n.find(&id, 1));
+        assert_node_list_eq(&[n.buckets[1].data().nth(2).unwrap()], &n.find(&id, 1));
     }
 
     #[test]
-    #[should_panic(expected = "greater than the hash size")]
     fn test_nodetable_find_overflow() {
         let mut id1 = Vec::with_capacity(DEFAULT_HASH_SIZE / 8);
         let mut id2 = Vec::with_capacity(DEFAULT_HASH_SIZE / 8);
@@ -295,7 +762,21 @@ mod test {
             id2.push(0);
         }
         let mut n = KNodeTable::new(id1);
-        n.update(&test::new_node(id2));
+        assert_eq!(UpdateResult::Rejected, n.update(&test::new_node(id2)));
+        assert!(n.is_empty());
+    }
+
+    #[test]
+    fn test_nodetable_update_rejects_bad_ids() {
+        let mut n = KNodeTable::new(test::make_id(42));
+        let this = test::new_node(test::make_id(42));
+        assert_eq!(UpdateResult::Rejected, n.update(&this));
+        let longer = test::new_node(vec![1, 2]);
+        assert_eq!(UpdateResult::Rejected, n.update(&longer));
+        assert_eq!(None, n.bucket_for(&longer.id));
+        let empty = test::new_node(vec![]);
+        assert_eq!(UpdateResult::Rejected, n.update(&empty));
+        assert!(n.is_empty());
     }
 
     #[test]
@@ -304,11 +785,11 @@ mod test {
         let node1 = test::new_node(test::make_id(0b0101));
         let node2 = test::new_node(test::make_id(0b1010));
         let node3 = test::new_node(test::make_id(0b1110));
-        assert!(n.update(&node1));
-        assert!(n.update(&node2));
-        assert!(n.update(&node3));
-        assert_node_list_eq(&vec![&node3], &n.find(&test::make_id(0b1111), 1));
-        assert_node_list_eq(&vec![&node2], &n.find(&test::make_id(0b1011), 1));
+        assert!(n.update(&node1).is_stored());
+        assert!(n.update(&node2).is_stored());
+        assert!(n.update(&node3).is_stored());
+        assert_node_list_eq(&[&node3], &n.find(&test::make_id(0b1111), 1));
+        assert_node_list_eq(&[&node2], &n.find(&test::make_id(0b1011), 1));
     }
 
     #[test]
@@ -316,9 +797,209 @@ mod test {
         let mut n = KNodeTable::new_with_details(test::make_id(42), 1, DEFAULT_HASH_SIZE);
         let node = test::new_node(test::make_id(41));
         n.update(&node);
-        assert_eq!(1, n.buckets[1].data.len());
+        assert_eq!(1, n.buckets[1].data().len());
         n.update(&node);
-        assert_eq!(1, n.buckets[1].data.len());
+        assert_eq!(1, n.buckets[1].data().len());
+    }
+
+    #[test]
+    fn test_nodetable_remove() {
+        let mut n = KNodeTable::new(test::make_id(42));
+        let node1 = test::new_node(test::make_id(41));
+        let node2 = test::new_node_with_port(test::make_id(43), 8009);
+        let node3 = test::new_node(test::make_id(40));
+        assert!(n.update(&node1).is_stored());
+        assert!(n.update(&node2).is_stored());
+        assert!(n.update(&node3).is_stored());
+
+        assert_eq!(node1.id, n.remove(&node1.id).unwrap().id);
+        assert!(n.remove(&node1.id).is_none());
+        assert!(n.remove(&test::make_id(42)).is_none());
+        assert_node_list_eq(&[&node3, &node2], &n.find(&test::make_id(41), 10));
+
+        let removed = n.remove_by_address(&node3.address);
+        assert_node_list_eq(&[&node3], &removed);
+        assert_node_list_eq(&[&node2], &n.find(&test::make_id(41), 10));
+        assert!(n.remove_by_address(&node3.address).is_empty());
+    }
+
+    #[test]
+    fn test_nodetable_introspection() {
+        let mut n = KNodeTable::new_with_details(test::make_id(42), 1, DEFAULT_HASH_SIZE);
+        assert!(n.is_empty());
+        let node1 = test::new_node(test::make_id(41));
+        let node2 = test::new_node(test::make_id(40));
+        let node3 = test::new_node(test::make_id(43));
+        assert!(n.update(&node1).is_stored());
+        assert_eq!(UpdateResult::Full, n.update(&node2));
+        assert!(n.update(&node3).is_stored());
+
+        assert_eq!(2, n.len());
+        assert!(n.contains(&node1.id));
+        assert!(!n.contains(&node2.id));
+        assert!(!n.contains(&test::make_id(42)));
+        assert_eq!(node3.address, n.get(&node3.id).unwrap().address);
+
+        let mut ids: Vec<_> = n.nodes().map(|n| n.id.clone()).collect();
+        ids.sort();
+        assert_eq!(vec![node1.id.clone(), node3.id.clone()], ids);
+
+        assert_eq!(Some(1), n.bucket_for(&node2.id));
+        assert_eq!(None, n.bucket_for(&test::make_id(42)));
+        // IDs outside of the hash size are not in the table
+        let small = KNodeTable::<u64, ()>::new_with_details(1, 2, 8);
+        assert_eq!(Some(7), small.bucket_for(&0xff));
+        assert_eq!(None, small.bucket_for(&0x100));
+        assert!(!small.contains(&0x100));
+        assert!(small.get(&0x100).is_none());
+        assert!(small.version(&0x100).is_none());
+        let stats = n.bucket_stats();
+        assert_eq!(DEFAULT_HASH_SIZE, stats.len());
+        assert_eq!(
+            BucketStats {
+                index: 0,
+                len: 1,
+                capacity: 1,
+            },
+            stats[0]
+        );
+        assert_eq!(1, stats[1].len);
+        assert!(stats[2..].iter().all(|s| s.len == 0));
+    }
+
+    #[test]
+    fn test_nodetable_address_limits_bucket() {
+        let mut n = KNodeTable::new(test::make_id(0b1000_0000));
+        n.set_address_limits(AddressLimits {
+            per_bucket_ip: Some(2),
+            ..Default::default()
+        });
+        // All of these fall into bucket 7
+        assert_eq!(
+            UpdateResult::Added,
+            n.update(&test::new_node_with_port(test::make_id(1), 1))
+        );
+        assert_eq!(
+            UpdateResult::Added,
+            n.update(&test::new_node_with_port(test::make_id(2), 2))
+        );
+        assert_eq!(
+            UpdateResult::Rejected,
+            n.update(&test::new_node_with_port(test::make_id(3), 3))
+        );
+        // Known nodes are still refreshed
+        assert_eq!(
+            UpdateResult::Updated,
+            n.update(&test::new_node_with_port(test::make_id(1), 4))
+        );
+        // Other bucket is not affected
+        assert_eq!(
+            UpdateResult::Added,
+            n.update(&test::new_node_with_port(test::make_id(0b1000_0001), 5))
+        );
+        assert_eq!(3, n.len());
+    }
+
+    #[test]
+    fn test_nodetable_address_limits_table_prefix() {
+        let mut n = KNodeTable::new(test::make_id(0b1000_0000));
+        n.set_address_limits(AddressLimits {
+            per_table_prefix: Some(2),
+            ..Default::default()
+        });
+        let node_at = |id, addr: &str| Node {
+            id: test::make_id(id),
+            address: addr.parse::<net::SocketAddr>().unwrap(),
+        };
+        assert_eq!(UpdateResult::Added, n.update(&node_at(1, "10.0.0.1:1")));
+        assert_eq!(
+            UpdateResult::Added,
+            n.update(&node_at(0b1000_0001, "10.0.0.2:1"))
+        );
+        assert_eq!(
+            UpdateResult::Rejected,
+            n.update(&node_at(0b1000_0010, "10.0.0.3:1"))
+        );
+        assert_eq!(
+            UpdateResult::Added,
+            n.update(&node_at(0b1000_0010, "10.0.1.3:1"))
+        );
+        assert_eq!(UpdateResult::Added, n.update(&node_at(2, "[fe80::1]:1")));
+        assert_eq!(UpdateResult::Added, n.update(&node_at(3, "[fe80::2]:1")));
+        assert_eq!(
+            UpdateResult::Rejected,
+            n.update(&node_at(4, "[fe80::1:2]:1"))
+        );
+        assert_eq!(
+            UpdateResult::Added,
+            n.update(&node_at(4, "[fe80:0:0:1::2]:1"))
+        );
+    }
+
+    #[test]
+    fn test_nodetable_address_limits_counts() {
+        let mut n = KNodeTable::new(test::make_id(0b1000_0000));
+        let node_at = |id, addr: &str| Node {
+            id: test::make_id(id),
+            address: addr.parse::<net::SocketAddr>().unwrap(),
+        };
+        n.update(&node_at(1, "10.0.0.1:1"));
+        n.update(&node_at(0b1000_0001, "10.0.0.1:2"));
+        // Nodes already in the table count
+        n.set_address_limits(AddressLimits {
+            per_table_ip: Some(2),
+            ..Default::default()
+        });
+        let moving = node_at(0b1000_0010, "10.0.0.1:3");
+        assert_eq!(UpdateResult::Rejected, n.update(&moving));
+        assert!(n.remove(&test::make_id(1)).is_some());
+        assert_eq!(UpdateResult::Added, n.update(&moving));
+
+        assert_eq!(1, n.remove_by_address(&"10.0.0.1:2".parse().unwrap()).len());
+        assert_eq!(
+            UpdateResult::Updated,
+            n.update(&node_at(0b1000_0010, "10.0.0.5:1"))
+        );
+        assert_eq!(UpdateResult::Added, n.update(&node_at(2, "10.0.0.1:4")));
+        assert_eq!(UpdateResult::Added, n.update(&node_at(3, "10.0.0.1:5")));
+        assert_eq!(UpdateResult::Rejected, n.update(&node_at(4, "10.0.0.1:6")));
+        assert_eq!(3, n.len());
+    }
+
+    #[test]
+    fn test_nodetable_estimate_size() {
+        let mut n = KNodeTable::<u64, ()>::new_with_details(0, 2, 8);
+        assert!(n.estimate_size().is_none());
+        for id in &[1, 2, 4] {
+            n.update(&Node {
+                id: *id,
+                address: (),
+            });
+        }
+        // No full bucket, the table may hold all nodes
+        assert!(n.estimate_size().is_none());
+        n.update(&Node { id: 5, address: () });
+        // Buckets 0 and 1 are not full and cover a quarter of the space
+        let estimate = n.estimate_size().unwrap();
+        assert_eq!(128.0, estimate.estimate);
+        assert_eq!(2, estimate.samples);
+        assert_eq!(4.0, estimate.low);
+        assert!(estimate.high > 300.0);
+
+        // 4 nodes within distance 8 from the target
+        let closest: Vec<_> = [0x81, 0x82, 0x84, 0x87]
+            .iter()
+            .map(|id| Node {
+                id: *id,
+                address: (),
+            })
+            .collect();
+        n.record_lookup(&0x80, &closest);
+        n.record_lookup(&0x80, &[]);
+        let estimate = n.estimate_size().unwrap();
+        assert_eq!(6, estimate.samples);
+        assert!(estimate.estimate > 128.0 && estimate.estimate < 182.0);
+        assert!(estimate.low < estimate.estimate && estimate.estimate < estimate.high);
     }
 
     #[test]
@@ -333,7 +1014,7 @@ mod test {
     #[test]
     fn test_kbucket_new() {
         let b = KBucket::<TestsIdType, net::SocketAddr>::new(3);
-        assert_eq!(0, b.data.len());
+        assert_eq!(0, b.data().len());
         assert_eq!(3, b.size);
     }
 
@@ -341,25 +1022,74 @@ mod test {
     fn test_kbucket_update_unknown() {
         let mut b = prepare(1);
         let node = test::new_node(test::make_id(42));
-        assert!(b.update(&node));
-        assert_eq!(2, b.data.len());
-        assert_eq!(node.id, b.data[1].id);
+        assert!(b.update(&node).is_stored());
+        assert_eq!(2, b.data().len());
+        assert_eq!(node.id, b.data().nth(1).unwrap().id);
     }
 
     #[test]
     fn test_kbucket_update_known() {
         let mut b = prepare(2);
         let node = test::new_node(test::make_id(0));
-        assert!(b.update(&node));
-        assert_eq!(2, b.data.len());
-        assert_eq!(node.id, b.data[1].id);
+        assert!(b.update(&node).is_stored());
+        assert_eq!(2, b.data().len());
+        assert_eq!(node.id, b.data().nth(1).unwrap().id);
     }
 
     #[test]
     fn test_kbucket_update_conflict() {
         let mut b = prepare(3); // 3 is size
         let node = test::new_node(test::make_id(42));
-        assert!(!b.update(&node))
+        assert_eq!(UpdateResult::Full, b.update(&node));
+    }
+
+    #[test]
+    fn test_kbucket_update_known_middle() {
+        let mut b = prepare(3);
+        assert!(b.update(&test::new_node(test::make_id(1))).is_stored());
+        let ids: Vec<_> = b.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(
+            vec![test::make_id(0), test::make_id(2), test::make_id(1)],
+            ids
+        );
+        assert!(b.update(&test::new_node(test::make_id(0))).is_stored());
+        let ids: Vec<_> = b.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(
+            vec![test::make_id(2), test::make_id(1), test::make_id(0)],
+            ids
+        );
+    }
+
+    #[test]
+    fn test_kbucket_remove() {
+        let mut b = prepare(3);
+        assert_eq!(test::make_id(1), b.remove(&test::make_id(1)).unwrap().id);
+        assert!(b.remove(&test::make_id(1)).is_none());
+        assert!(b.update(&test::new_node(test::make_id(42))).is_stored());
+        let ids: Vec<_> = b.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(
+            vec![test::make_id(0), test::make_id(2), test::make_id(42)],
+            ids
+        );
+    }
+
+    #[test]
+    fn test_kbucket_pop_front_reuses_space() {
+        let mut b = prepare(3);
+        assert!(b.update(&test::new_node(test::make_id(0))).is_stored());
+        assert_eq!(test::make_id(1), b.pop_front().unwrap().id);
+        assert!(b.update(&test::new_node(test::make_id(42))).is_stored());
+        let ids: Vec<_> = b.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(
+            vec![test::make_id(2), test::make_id(0), test::make_id(42)],
+            ids
+        );
+        assert!(b.update(&test::new_node(test::make_id(2))).is_stored());
+        assert_eq!(test::make_id(0), b.pop_front().unwrap().id);
+        assert_eq!(test::make_id(42), b.pop_front().unwrap().id);
+        assert_eq!(test::make_id(2), b.pop_front().unwrap().id);
+        assert!(b.pop_front().is_none());
+        assert!(b.is_empty());
     }
 
     #[test]
@@ -367,9 +1097,10 @@ mod test {
         let b = prepare(3);
         // Nodes with ID's 0, 1, 2; assume our ID is also 2 (impossible IRL)
         let id = test::make_id(2);
+        let data: Vec<_> = b.data().collect();
         // 0 xor 2 = 2, 1 xor 2 = 3, 2 xor 2 = 0
-        assert_node_list_eq(&[&b.data[2]], &b.find(&id, 1));
-        assert_node_list_eq(&[&b.data[2], &b.data[0]], &b.find(&id, 2));
+        assert_node_list_eq(&[data[2]], &b.find(&id, 1));
+        assert_node_list_eq(&[data[2], data[0]], &b.find(&id, 2));
     }
 
     #[test]
@@ -377,7 +1108,25 @@ mod test {
         let b = prepare(3);
         // Nodes with ID's 0, 1, 2; assume our ID is also 2 (impossible IRL)
         let id = test::make_id(2);
+        let data: Vec<_> = b.data().collect();
         // 0 xor 2 = 2, 1 xor 2 = 3, 2 xor 2 = 0
-        assert_node_list_eq(&[&b.data[2], &b.data[0], &b.data[1]], &b.find(&id, 100));
+        assert_node_list_eq(&[data[2], data[0], data[1]], &b.find(&id, 100));
+    }
+
+    #[test]
+    fn test_kbucket_version() {
+        let mut b = prepare(3);
+        let id = test::make_id(1);
+        let version = Version::current();
+        assert!(b.version(&id).is_none());
+        assert!(b.set_version(&id, version.clone()));
+        assert!(!b.set_version(&test::make_id(42), version.clone()));
+        // Refreshing the node keeps its version
+        assert!(b.update(&test::new_node(id.clone())).is_stored());
+        assert_eq!(Some(&version), b.version(&id));
+        // Evicted nodes are forgotten with their versions
+        b.remove(&id);
+        assert!(b.update(&test::new_node(id.clone())).is_stored());
+        assert!(b.version(&id).is_none());
     }
 }
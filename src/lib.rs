@@ -23,19 +23,53 @@
 #![crate_name = "dht"]
 #![crate_type = "lib"]
 
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+extern crate ed25519_dalek;
 #[macro_use]
 extern crate log;
 extern crate rand;
 extern crate rustc_serialize;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+extern crate sha1;
 
+pub use base::BucketStats;
+pub use base::GenericAPI;
+pub use base::GenericAddress;
 pub use base::GenericId;
 pub use base::GenericNodeTable;
 pub use base::Node;
+pub use base::SizeEstimate;
+pub use base::UpdateResult;
+pub use dualstack::DualStackTable;
+pub use knodetable::AddressLimits;
 pub use knodetable::KNodeTable;
+pub use ratelimit::RateLimit;
+pub use ratelimit::RateLimits;
 pub use service::Service;
 
+mod addrvote;
+#[cfg(feature = "async")]
+pub mod async_service;
+pub mod banlist;
 mod base;
+pub mod bep42;
+pub mod bep44;
+pub mod compact;
+pub mod crawler;
+mod dualstack;
 mod knodetable;
+pub mod lookup;
+pub mod multivalue;
 pub mod protocol;
+mod ratelimit;
+#[cfg(feature = "serde")]
+mod serde_impls;
 pub mod service;
+pub mod skademlia;
+mod token;
 mod utils;
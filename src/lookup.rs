@@ -0,0 +1,431 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Protocol-agnostic iterative lookups.
+//!
+//! A lookup starts with seed nodes (usually from `GenericNodeTable::find`)
+//! and repeatedly queries the closest not yet queried nodes, until the
+//! closest `count` known nodes have all been queried or a value is found.
+//!
+//! With several paths the lookup runs as `d` independent lookups over
+//! disjoint sets of nodes, as in S/Kademlia: a node is only ever queried
+//! by one of them. A malicious node can then only mislead the path that
+//! found it, and the lookup succeeds if any path reaches the target.
+
+use std::collections::HashSet;
+
+use super::service::FindResult;
+use super::{GenericId, Node};
+
+static DEFAULT_COUNT: usize = 16;
+static DEFAULT_ALPHA: usize = 3;
+
+/// Iterative lookup of nodes or a value.
+pub struct Lookup<TId, TAddr> {
+    target: TId,
+    count: usize,
+    alpha: usize,
+    paths: Vec<Path<TId, TAddr>>,
+    seen: HashSet<TId>,
+}
+
+struct Path<TId, TAddr> {
+    // Sorted by distance to the target
+    shortlist: Vec<Candidate<TId, TAddr>>,
+}
+
+struct Candidate<TId, TAddr> {
+    distance: TId,
+    node: Node<TId, TAddr>,
+    state: State,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Responded,
+    Failed,
+}
+
+impl<TId, TAddr> Lookup<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Clone,
+{
+    /// Create a single path lookup with default parameters.
+    pub fn new(target: TId, seeds: Vec<Node<TId, TAddr>>) -> Lookup<TId, TAddr> {
+        Lookup::new_with_details(target, seeds, DEFAULT_COUNT, DEFAULT_ALPHA, 1)
+    }
+
+    /// Create a lookup.
+    ///
+    /// `count` -- number of closest nodes to find,
+    /// `alpha` -- number of queries per path in one round,
+    /// `paths` -- number of disjoint paths; seeds are split between them.
+    pub fn new_with_details(
+        target: TId,
+        seeds: Vec<Node<TId, TAddr>>,
+        count: usize,
+        alpha: usize,
+        paths: usize,
+    ) -> Lookup<TId, TAddr> {
+        assert!(count > 0 && alpha > 0 && paths > 0);
+        let mut lookup = Lookup {
+            target,
+            count,
+            alpha,
+            paths: (0..paths)
+                .map(|_| Path {
+                    shortlist: Vec::new(),
+                })
+                .collect(),
+            seen: HashSet::new(),
+        };
+        let mut next = 0;
+        for node in seeds {
+            if lookup.add(next, node) {
+                next = (next + 1) % paths;
+            }
+        }
+        lookup
+    }
+
+    /// Never query node with given ID (e.g. our own).
+    pub fn exclude(&mut self, id: TId) {
+        self.seen.insert(id);
+    }
+
+    /// Run the lookup.
+    ///
+    /// `query` is called for every node to ask for the target. It returns
+    /// `FindResult::Nothing` if the node failed to respond.
+    ///
+    /// Returns the first value found or the closest nodes that responded,
+    /// merged from all paths.
+    pub fn run<TData, F>(mut self, mut query: F) -> FindResult<TId, TAddr, TData>
+    where
+        F: FnMut(&Node<TId, TAddr>, &TId) -> FindResult<TId, TAddr, TData>,
+    {
+        loop {
+            let mut active = false;
+            for path in 0..self.paths() {
+                for node in self.next_batch(path) {
+                    active = true;
+                    let result = query(&node, &self.target);
+                    if let Some(values) = self.record(path, &node.id, result) {
+                        return FindResult::Value(values);
+                    }
+                }
+            }
+            if !active {
+                break;
+            }
+        }
+        self.finish()
+    }
+
+    /// Target of the lookup.
+    pub fn target(&self) -> &TId {
+        &self.target
+    }
+
+    /// Number of disjoint paths.
+    pub fn paths(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Nodes to query next on the path, empty if the path is done.
+    ///
+    /// For driving the lookup manually instead of `run`, e.g. with
+    /// asynchronous queries. Every node must then be passed to `record`.
+    pub fn next_batch(&self, path: usize) -> Vec<Node<TId, TAddr>> {
+        self.paths[path].next_batch(self.count, self.alpha)
+    }
+
+    /// Record the result of querying a node from `next_batch(path)`.
+    ///
+    /// All nodes in the result become candidates, filter out unwanted ones
+    /// (e.g. banned) beforehand. Returns the values, if found.
+    pub fn record<TData>(
+        &mut self,
+        path: usize,
+        id: &TId,
+        result: FindResult<TId, TAddr, TData>,
+    ) -> Option<Vec<TData>> {
+        let state = match result {
+            FindResult::Value(values) => {
+                debug!("Found value for {:?} at {:?}", self.target, id);
+                return Some(values);
+            }
+            FindResult::ClosestNodes(nodes) => {
+                for found in nodes {
+                    self.add(path, found);
+                }
+                State::Responded
+            }
+            FindResult::Nothing => State::Failed,
+        };
+        self.paths[path].set_state(id, state);
+        None
+    }
+
+    /// Finish the lookup without a value.
+    ///
+    /// Returns the closest nodes that responded, merged from all paths.
+    pub fn finish<TData>(self) -> FindResult<TId, TAddr, TData> {
+        let mut result: Vec<_> = self
+            .paths
+            .iter()
+            .flat_map(|p| {
+                p.shortlist
+                    .iter()
+                    .filter(|c| c.state == State::Responded)
+                    .take(self.count)
+            })
+            .collect();
+        result.sort_by(|a, b| a.distance.cmp(&b.distance));
+        result.truncate(self.count);
+        if result.is_empty() {
+            FindResult::Nothing
+        } else {
+            FindResult::ClosestNodes(result.into_iter().map(|c| c.node.clone()).collect())
+        }
+    }
+
+    fn add(&mut self, path_idx: usize, node: Node<TId, TAddr>) -> bool {
+        if !self.seen.insert(node.id.clone()) {
+            return false;
+        }
+        let distance = node.id.bitxor(&self.target);
+        let shortlist = &mut self.paths[path_idx].shortlist;
+        let pos = match shortlist.binary_search_by(|c| c.distance.cmp(&distance)) {
+            Ok(pos) | Err(pos) => pos,
+        };
+        shortlist.insert(
+            pos,
+            Candidate {
+                distance,
+                node,
+                state: State::Pending,
+            },
+        );
+        true
+    }
+}
+
+impl<TId, TAddr> Path<TId, TAddr>
+where
+    TId: GenericId,
+    TAddr: Clone,
+{
+    fn next_batch(&self, count: usize, alpha: usize) -> Vec<Node<TId, TAddr>> {
+        self.shortlist
+            .iter()
+            .filter(|c| c.state != State::Failed)
+            .take(count)
+            .filter(|c| c.state == State::Pending)
+            .take(alpha)
+            .map(|c| c.node.clone())
+            .collect()
+    }
+
+    fn set_state(&mut self, id: &TId, state: State) {
+        if let Some(c) = self.shortlist.iter_mut().find(|c| c.node.id == *id) {
+            c.state = state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rand::{Rng, SeedableRng, StdRng};
+
+    use super::super::service::FindResult;
+    use super::super::{GenericNodeTable, KNodeTable, Node};
+    use super::Lookup;
+
+    type SimNode = Node<u64, usize>;
+
+    fn node(id: u64) -> SimNode {
+        Node {
+            id,
+            address: id as usize,
+        }
+    }
+
+    #[test]
+    fn test_lookup_finds_closest() {
+        // Every node knows the node with ID one less than its own
+        let result: FindResult<u64, usize, ()> =
+            Lookup::new_with_details(0, vec![node(100)], 2, 1, 1)
+                .run(|n, _| FindResult::ClosestNodes((1..n.id).rev().take(1).map(node).collect()));
+        match result {
+            FindResult::ClosestNodes(nodes) => {
+                assert_eq!(vec![1, 2], nodes.iter().map(|n| n.id).collect::<Vec<_>>())
+            }
+            _ => panic!("wrong result"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_value_and_failures() {
+        let result = Lookup::new(0u64, vec![node(4), node(8)]).run(|n, _| match n.id {
+            4 => FindResult::Nothing,
+            8 => FindResult::ClosestNodes(vec![node(4), node(3)]),
+            3 => FindResult::Value(vec!["value"]),
+            _ => panic!("unexpected query to {}", n.id),
+        });
+        match result {
+            FindResult::Value(values) => assert_eq!(vec!["value"], values),
+            _ => panic!("wrong result"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_exclude_and_nothing() {
+        let mut lookup = Lookup::new(0u64, vec![node(4)]);
+        lookup.exclude(2);
+        let result: FindResult<u64, usize, ()> = lookup.run(|n, _| match n.id {
+            4 => FindResult::ClosestNodes(vec![node(2)]),
+            _ => panic!("unexpected query to {}", n.id),
+        });
+        match result {
+            FindResult::ClosestNodes(nodes) => assert_eq!(4, nodes[0].id),
+            _ => panic!("wrong result"),
+        }
+
+        let result: FindResult<u64, usize, ()> =
+            Lookup::new(0u64, vec![node(4)]).run(|_, _| FindResult::Nothing);
+        match result {
+            FindResult::Nothing => {}
+            _ => panic!("wrong result"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_paths_are_disjoint() {
+        let mut queried = HashMap::new();
+        let seeds = (1..9).map(|i| node(i << 8)).collect();
+        let _: FindResult<u64, usize, ()> =
+            Lookup::new_with_details(0, seeds, 4, 2, 4).run(|n, _| {
+                *queried.entry(n.id).or_insert(0) += 1;
+                // Everybody returns the same nodes
+                FindResult::ClosestNodes((1..6).map(node).collect())
+            });
+        assert!(queried.values().all(|count| *count == 1));
+        assert!(queried.contains_key(&1));
+    }
+
+    /// Simulated network where some nodes lie about closest nodes.
+    ///
+    /// Malicious nodes answer with made up nodes very close to the target,
+    /// which are also malicious, trying to steer the lookup away from the
+    /// honest nodes holding the value.
+    struct Network {
+        tables: Vec<KNodeTable<u64, usize>>,
+        ids: Vec<u64>,
+        malicious: Vec<bool>,
+    }
+
+    impl Network {
+        fn new(rng: &mut StdRng, size: usize, malicious_share: f64) -> Network {
+            let ids: Vec<u64> = (0..size).map(|_| rng.gen()).collect();
+            let malicious = (0..size)
+                .map(|_| rng.gen::<f64>() < malicious_share)
+                .collect();
+            let tables = ids
+                .iter()
+                .map(|own| {
+                    let mut table = KNodeTable::new_with_details(*own, 8, 64);
+                    for (address, id) in ids.iter().enumerate() {
+                        if id != own {
+                            table.update(&Node { id: *id, address });
+                        }
+                    }
+                    table
+                })
+                .collect();
+            Network {
+                tables,
+                ids,
+                malicious,
+            }
+        }
+
+        fn query(
+            &self,
+            node: &SimNode,
+            target: u64,
+            holders: &[u64],
+        ) -> FindResult<u64, usize, ()> {
+            if self.malicious[node.address] {
+                let liar = node.address;
+                FindResult::ClosestNodes(
+                    (1..9)
+                        .map(|i| Node {
+                            id: target ^ i,
+                            address: liar,
+                        })
+                        .collect(),
+                )
+            } else if holders.contains(&node.id) {
+                FindResult::Value(vec![()])
+            } else {
+                FindResult::ClosestNodes(self.tables[node.address].find(&target, 8))
+            }
+        }
+
+        fn success_rate(&self, rng: &mut StdRng, paths: usize, alpha: usize) -> f64 {
+            let trials = 200;
+            let mut success = 0;
+            for _ in 0..trials {
+                let target: u64 = rng.gen();
+                let mut honest: Vec<u64> = self
+                    .ids
+                    .iter()
+                    .enumerate()
+                    .filter(|&(address, _)| !self.malicious[address])
+                    .map(|(_, id)| *id)
+                    .collect();
+                honest.sort_by_key(|id| id ^ target);
+                honest.truncate(3);
+
+                let start = loop {
+                    let address = rng.gen_range(0, self.ids.len());
+                    if !self.malicious[address] {
+                        break address;
+                    }
+                };
+                let seeds = self.tables[start].find(&target, 8);
+                let mut lookup = Lookup::new_with_details(target, seeds, 8, alpha, paths);
+                lookup.exclude(self.ids[start]);
+                if let FindResult::Value(..) = lookup.run(|n, t| self.query(n, *t, &honest)) {
+                    success += 1;
+                }
+            }
+            success as f64 / trials as f64
+        }
+    }
+
+    #[test]
+    fn test_disjoint_lookup_resists_liars() {
+        let mut rng = StdRng::from_seed([42; 32]);
+        let network = Network::new(&mut rng, 500, 0.2);
+        let single = network.success_rate(&mut rng, 1, 3);
+        let disjoint = network.success_rate(&mut rng, 4, 3);
+        assert!(
+            disjoint > single + 0.2,
+            "single path: {}, disjoint paths: {}",
+            single,
+            disjoint
+        );
+        assert!(disjoint > 0.8, "disjoint paths: {}", disjoint);
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Storage of several values per ID, e.g. for peer lists.
+//!
+//! Every publisher has at most one value under an ID, storing again
+//! replaces it and extends its lifetime. `Service` identifies publishers
+//! by the addresses requests came from. Find replies carry a random
+//! sample of the values, so that all of them get a chance to be returned.
+
+use std::collections::hash_map::Keys;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+
+use rand::{self, Rng};
+
+static DEFAULT_MAX_VALUES: usize = 256;
+static DEFAULT_MAX_REPLY: usize = 32;
+static DEFAULT_TTL_SECS: u64 = 30 * 60;
+
+/// Value with its publisher and expiry time.
+#[derive(Clone, Debug)]
+pub struct StoredValue<TPublisher, TData> {
+    pub value: TData,
+    pub publisher: TPublisher,
+    pub expires: SystemTime,
+}
+
+/// Values stored under IDs, see module documentation.
+pub struct MultiValueStore<TId, TData, TPublisher = TId> {
+    max_values: usize,
+    max_reply: usize,
+    ttl: Duration,
+    values: HashMap<TId, Vec<StoredValue<TPublisher, TData>>>,
+}
+
+impl<TId, TData, TPublisher> MultiValueStore<TId, TData, TPublisher> {
+    /// Create a store with default limits.
+    pub fn new() -> MultiValueStore<TId, TData, TPublisher> {
+        MultiValueStore::new_with_details(
+            DEFAULT_MAX_VALUES,
+            DEFAULT_MAX_REPLY,
+            Duration::from_secs(DEFAULT_TTL_SECS),
+        )
+    }
+
+    /// Create a store.
+    ///
+    /// `max_values` -- maximum number of values per ID,
+    /// `max_reply` -- maximum number of values in a find reply,
+    /// `ttl` -- time until a value expires, unless stored again.
+    pub fn new_with_details(
+        max_values: usize,
+        max_reply: usize,
+        ttl: Duration,
+    ) -> MultiValueStore<TId, TData, TPublisher> {
+        assert!(max_values > 0 && max_reply > 0);
+        MultiValueStore {
+            max_values,
+            max_reply,
+            ttl,
+            values: HashMap::new(),
+        }
+    }
+
+    /// Maximum number of values per ID.
+    pub fn max_values(&self) -> usize {
+        self.max_values
+    }
+    /// Maximum number of values in a find reply.
+    pub fn max_reply(&self) -> usize {
+        self.max_reply
+    }
+    /// Time until a value expires.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Total number of values, including expired ones not purged yet.
+    pub fn len(&self) -> usize {
+        self.values.values().map(Vec::len).sum()
+    }
+    /// Check if no values are stored.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Iterate over IDs with values, including expired ones not purged yet.
+    pub fn keys(&self) -> Keys<'_, TId, Vec<StoredValue<TPublisher, TData>>> {
+        self.values.keys()
+    }
+
+    /// Drop expired values, returns IDs that had values expired.
+    pub fn purge_expired(&mut self) -> Vec<TId>
+    where
+        TId: Clone,
+    {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+        for (id, values) in self.values.iter_mut() {
+            let before = values.len();
+            values.retain(|v| v.expires > now);
+            if values.len() < before {
+                expired.push(id.clone());
+            }
+        }
+        self.values.retain(|_, values| !values.is_empty());
+        expired
+    }
+}
+
+impl<TId, TData, TPublisher> MultiValueStore<TId, TData, TPublisher>
+where
+    TId: Hash + Eq,
+    TPublisher: PartialEq,
+{
+    /// Store a value from the publisher under the ID.
+    ///
+    /// Replaces the previous value of the publisher. If there is no space
+    /// left, the value closest to expiry is dropped.
+    pub fn insert(&mut self, id: TId, publisher: TPublisher, value: TData) {
+        let now = SystemTime::now();
+        let max_values = self.max_values;
+        let values = self.values.entry(id).or_default();
+        values.retain(|v| v.publisher != publisher && v.expires > now);
+        if values.len() >= max_values {
+            if let Some(oldest) = (0..values.len()).min_by_key(|&i| values[i].expires) {
+                values.swap_remove(oldest);
+            }
+        }
+        values.push(StoredValue {
+            value,
+            publisher,
+            expires: now + self.ttl,
+        });
+    }
+
+    /// Values stored under the ID, that have not expired.
+    pub fn get(&self, id: &TId) -> Vec<&StoredValue<TPublisher, TData>> {
+        let now = SystemTime::now();
+        match self.values.get(id) {
+            Some(values) => values.iter().filter(|v| v.expires > now).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Remove the value of the publisher under the ID.
+    pub fn remove(&mut self, id: &TId, publisher: &TPublisher) -> Option<TData> {
+        let values = self.values.get_mut(id)?;
+        let idx = values.iter().position(|v| v.publisher == *publisher)?;
+        let removed = values.swap_remove(idx);
+        if values.is_empty() {
+            self.values.remove(id);
+        }
+        Some(removed.value)
+    }
+
+    /// Random sample of at most `max_reply` values under the ID.
+    pub fn sample(&self, id: &TId) -> Vec<TData>
+    where
+        TData: Clone,
+    {
+        let mut values = self.get(id);
+        rand::thread_rng().shuffle(&mut values);
+        values
+            .into_iter()
+            .take(self.max_reply)
+            .map(|v| v.value.clone())
+            .collect()
+    }
+}
+
+impl<TId, TData, TPublisher> Default for MultiValueStore<TId, TData, TPublisher> {
+    fn default() -> MultiValueStore<TId, TData, TPublisher> {
+        MultiValueStore::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use super::MultiValueStore;
+
+    #[test]
+    fn test_insert_get() {
+        let mut store = MultiValueStore::new();
+        assert!(store.is_empty());
+        store.insert(1, 10, "a");
+        store.insert(1, 11, "b");
+        store.insert(2, 10, "c");
+        assert_eq!(3, store.len());
+
+        let values: HashSet<_> = store.get(&1).iter().map(|v| v.value).collect();
+        assert_eq!(vec!["a", "b"].into_iter().collect::<HashSet<_>>(), values);
+        assert!(store.get(&3).is_empty());
+
+        // Publisher replaces its value
+        store.insert(1, 10, "d");
+        assert_eq!(3, store.len());
+        let mut values: Vec<_> = store.get(&1).iter().map(|v| v.value).collect();
+        values.sort();
+        assert_eq!(vec!["b", "d"], values);
+
+        assert_eq!(Some("b"), store.remove(&1, &11));
+        assert_eq!(None, store.remove(&1, &11));
+        assert_eq!(Some("d"), store.remove(&1, &10));
+        assert_eq!(1, store.len());
+    }
+
+    #[test]
+    fn test_max_values() {
+        let mut store = MultiValueStore::new_with_details(2, 2, Duration::from_secs(60));
+        store.insert(1, 10, "a");
+        store.insert(1, 11, "b");
+        store.insert(1, 12, "c");
+        let mut values: Vec<_> = store.get(&1).iter().map(|v| v.value).collect();
+        values.sort();
+        assert_eq!(vec!["b", "c"], values);
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut store = MultiValueStore::new_with_details(16, 3, Duration::from_secs(60));
+        for publisher in 0..10 {
+            store.insert(1, publisher, publisher);
+        }
+        let sample = store.sample(&1);
+        assert_eq!(3, sample.len());
+        assert_eq!(3, sample.iter().collect::<HashSet<_>>().len());
+        assert!(store.sample(&2).is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut store = MultiValueStore::new_with_details(16, 16, Duration::from_secs(0));
+        store.insert(1, 10, "a");
+        assert_eq!(1, store.len());
+        assert!(store.get(&1).is_empty());
+        assert!(store.sample(&1).is_empty());
+        assert_eq!(vec![1], store.purge_expired());
+        assert!(store.is_empty());
+        assert!(store.purge_expired().is_empty());
+    }
+}
@@ -8,37 +8,300 @@
 
 //! Generic protocol bits for implementing custom protocols.
 
+use std::net::SocketAddr;
+use std::time::Duration;
+
 use super::{GenericId, Node};
 
+/// Opaque write token, see `Response::token`.
+pub type Token = Vec<u8>;
+
+/// Version of the messages defined in this module.
+///
+/// Version 2 allows several values in `ResponsePayload::ValueFound`.
+pub static PROTOCOL_VERSION: u32 = 2;
+
+/// Feature of nodes answering `AddProvider` and `GetProviders`.
+pub static FEATURE_PROVIDERS: &str = "providers";
+/// Feature of nodes answering `SampleKeys` (BEP 51).
+pub static FEATURE_SAMPLE_KEYS: &str = "sample_keys";
+/// Maximum number of features kept from a node's version.
+pub static MAX_FEATURES: usize = 16;
+/// Maximum length of a feature name kept from a node's version.
+pub static MAX_FEATURE_LEN: usize = 32;
+
+/// Protocol version and optional features advertised by a node.
+///
+/// Version 0 means that the node did not advertise any.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Version {
+    pub number: u32,
+    /// Names of optional features, unknown ones are ignored.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub features: Vec<String>,
+}
+
+impl Version {
+    /// Version of this implementation with the optional features it has.
+    pub fn current() -> Version {
+        Version {
+            number: PROTOCOL_VERSION,
+            features: vec![
+                FEATURE_PROVIDERS.to_string(),
+                FEATURE_SAMPLE_KEYS.to_string(),
+            ],
+        }
+    }
+    /// Check if the optional feature is supported.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+    /// Copy of the version received from a node, suitable for storing.
+    ///
+    /// Keeps at most `MAX_FEATURES` features, dropping names longer than
+    /// `MAX_FEATURE_LEN`.
+    pub fn bounded(&self) -> Version {
+        Version {
+            number: self.number,
+            features: self
+                .features
+                .iter()
+                .filter(|f| f.len() <= MAX_FEATURE_LEN)
+                .take(MAX_FEATURES)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
 /// Payload in the request.
+///
+/// With serde, unknown methods are decoded as `Unknown` in self-describing
+/// formats like JSON, binary formats fail on them.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize),
+    serde(bound(serialize = "TId: crate::compact::CompactId, TValue: ::serde::Serialize"))
+)]
 pub enum RequestPayload<TId, TValue> {
     Ping,
-    FindNode(TId),
-    FindValue(TId),
-    Store(TId, TValue),
+    FindNode(#[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))] TId),
+    FindValue(#[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))] TId),
+    /// Store a value, presenting a token received from the same node.
+    Store(
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))] TId,
+        TValue,
+        Token,
+    ),
+    /// Announce the caller as a provider of the ID, presenting a token.
+    AddProvider(
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))] TId,
+        Token,
+    ),
+    GetProviders(#[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))] TId),
+    /// Ask for a sample of stored keys and the nodes closest to the ID.
+    ///
+    /// See BEP 51 (DHT infohash indexing).
+    SampleKeys(#[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))] TId),
+    /// Method not known to the protocol implementation, with its name.
+    ///
+    /// Answered with a `METHOD_UNKNOWN` error.
+    Unknown(String),
+}
+
+impl<TId, TValue> RequestPayload<TId, TValue> {
+    /// Optional feature the receiver needs to answer the request.
+    pub fn feature(&self) -> Option<&'static str> {
+        match *self {
+            RequestPayload::AddProvider(..) | RequestPayload::GetProviders(..) => {
+                Some(FEATURE_PROVIDERS)
+            }
+            RequestPayload::SampleKeys(..) => Some(FEATURE_SAMPLE_KEYS),
+            _ => None,
+        }
+    }
 }
 
 /// Request structure.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "TId: crate::compact::CompactId, TAddr: ::serde::Serialize, TValue: ::serde::Serialize",
+        deserialize = "TId: crate::compact::CompactId, TAddr: ::serde::Deserialize<'de>, TValue: ::serde::Deserialize<'de>"
+    ))
+)]
 pub struct Request<TId, TAddr, TValue> {
     pub caller: Node<TId, TAddr>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::id"))]
     pub request_id: TId,
     pub payload: RequestPayload<TId, TValue>,
+    /// Version of the caller.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub version: Version,
+    /// Caller does not answer requests and must not be added to node tables.
+    ///
+    /// See BEP 43 (read-only DHT nodes).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub read_only: bool,
+    /// Address families of nodes the caller wants in find replies.
+    ///
+    /// `None` means the family of the address the request came from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub want: Option<Want>,
+    /// Signature of the caller, if messages are authenticated.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub signature: Option<Signature>,
 }
 
 /// Payload in the response.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "TId: crate::compact::CompactId, TAddr: ::serde::Serialize, TValue: ::serde::Serialize",
+        deserialize = "TId: crate::compact::CompactId, TAddr: ::serde::Deserialize<'de>, TValue: ::serde::Deserialize<'de>"
+    ))
+)]
 pub enum ResponsePayload<TId, TAddr, TValue> {
     NodesFound(Vec<Node<TId, TAddr>>),
-    ValueFound(TValue),
+    /// Values stored under the ID, see `service::FindResult::Value`.
+    ValueFound(Vec<TValue>),
+    /// Providers of the ID and the nodes closest to it.
+    ProvidersFound(Vec<Node<TId, TAddr>>, Vec<Node<TId, TAddr>>),
+    /// Sample of stored keys and the nodes closest to the requested ID.
+    KeysSampled(KeySample<TId>, Vec<Node<TId, TAddr>>),
     NoResult,
+    /// Request failed.
+    Error(ResponseError),
+}
+
+/// Random sample of keys stored on a node (BEP 51).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "TId: crate::compact::CompactId",
+        deserialize = "TId: crate::compact::CompactId"
+    ))
+)]
+pub struct KeySample<TId> {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::ids"))]
+    pub keys: Vec<TId>,
+    /// Total number of keys stored on the node.
+    pub total: usize,
+    /// Time before asking the node for a new sample.
+    pub interval: Duration,
+}
+
+/// Error returned instead of a result, with codes as in KRPC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResponseError {
+    pub code: u16,
+    pub message: String,
+}
+
+/// Generic error.
+pub static GENERIC_ERROR: u16 = 201;
+/// Server error.
+pub static SERVER_ERROR: u16 = 202;
+/// Protocol error, e.g. a malformed request or an invalid token.
+pub static PROTOCOL_ERROR: u16 = 203;
+/// Method unknown.
+pub static METHOD_UNKNOWN: u16 = 204;
+
+impl ResponseError {
+    pub fn new(code: u16, message: &str) -> ResponseError {
+        ResponseError {
+            code,
+            message: message.to_string(),
+        }
+    }
 }
 
 /// Response structure.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "TId: crate::compact::CompactId, TAddr: ::serde::Serialize, TValue: ::serde::Serialize",
+        deserialize = "TId: crate::compact::CompactId, TAddr: ::serde::Deserialize<'de>, TValue: ::serde::Deserialize<'de>"
+    ))
+)]
 pub struct Response<TId, TAddr, TValue> {
     pub request: Request<TId, TAddr, TValue>,
     pub responder: Node<TId, TAddr>,
     pub payload: ResponsePayload<TId, TAddr, TValue>,
+    /// Version of the responder.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub version: Version,
+    /// Token to present when storing a value on the responder.
+    ///
+    /// Only given in replies to find requests, valid for up to 10 minutes
+    /// for the requester's address.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub token: Option<Token>,
+    /// Address of the requester as seen by the responder.
+    ///
+    /// Lets nodes behind NAT learn their external address.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub observed_address: Option<TAddr>,
+    /// Signature of the responder, if messages are authenticated.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub signature: Option<Signature>,
 }
 
+/// Address families of nodes wanted in find replies (BEP 32 `want`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Want {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl Want {
+    /// Both IPv4 and IPv6 nodes.
+    pub fn both() -> Want {
+        Want {
+            ipv4: true,
+            ipv6: true,
+        }
+    }
+    /// Nodes of the same family as the address.
+    pub fn family_of(address: &SocketAddr) -> Want {
+        Want {
+            ipv4: address.is_ipv4(),
+            ipv6: address.is_ipv6(),
+        }
+    }
+}
+
+/// Signature over a message with the public key of its sender.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Signature {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::array"))]
+    pub public_key: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::array"))]
+    pub signature: [u8; 64],
+}
+
+/// Trait for authenticating incoming messages.
+///
+/// See `skademlia::SKademlia` for an implementation.
+pub trait Authenticator<TId, TAddr, TValue>: Send + Sync {
+    /// Check that request comes from its caller.
+    fn verify_request(&self, request: &Request<TId, TAddr, TValue>) -> bool;
+    /// Check that response comes from its responder.
+    fn verify_response(&self, response: &Response<TId, TAddr, TValue>) -> bool;
+}
+
+/// Parsed request or the error to report back.
+pub type ParseResult<TId, TAddr, TValue> = Result<Request<TId, TAddr, TValue>, ResponseError>;
+
 /// Trait for a protocol implementation.
 pub trait Protocol: Send {
     /// Value type.
@@ -46,7 +309,11 @@ pub trait Protocol: Send {
     type Addr: Send + Sync;
     type Value: Send + Sync;
     /// Parse request from binary data.
-    fn parse_request(&self, data: &[u8]) -> Request<Self::Id, Self::Addr, Self::Value>;
+    ///
+    /// Fails with `PROTOCOL_ERROR` or `METHOD_UNKNOWN` errors to report
+    /// back to the requester. Alternatively, unknown methods can be parsed
+    /// as `RequestPayload::Unknown` to be answered by `Handler`.
+    fn parse_request(&self, data: &[u8]) -> ParseResult<Self::Id, Self::Addr, Self::Value>;
     /// Format response to binary data.
-    fn format_response(&self, Response<Self::Id, Self::Addr, Self::Value>) -> Vec<u8>;
+    fn format_response(&self, response: Response<Self::Id, Self::Addr, Self::Value>) -> Vec<u8>;
 }
@@ -0,0 +1,278 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Token bucket rate limiting of incoming requests.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+// Forget idle sources when tracking more than this many of them, requests
+// from new sources are dropped if none of the tracked ones is idle
+static MAX_TRACKED_SOURCES: usize = 4096;
+// Minimum interval between looking for idle sources
+static PURGE_INTERVAL_MS: u64 = 1000;
+
+/// Token bucket parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Requests per second allowed on average.
+    pub rate: f64,
+    /// Maximum number of requests allowed in a burst.
+    pub burst: f64,
+}
+
+/// Rate limits for incoming requests. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    /// Limit for every source host, see `GenericAddress::host`.
+    pub per_source: Option<RateLimit>,
+    /// Limit for all requests together.
+    pub global: Option<RateLimit>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Rate limiter for requests from addresses of type `TAddr`.
+///
+/// Sources are told apart by the exact address, pass hosts to `check` to
+/// share limits between ports.
+pub struct RateLimiter<TAddr> {
+    limits: RateLimits,
+    global: Bucket,
+    sources: HashMap<TAddr, Bucket>,
+    purged_at: Instant,
+    dropped: u64,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.burst,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated_at = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refund(&mut self, limit: &RateLimit) {
+        self.tokens = (self.tokens + 1.0).min(limit.burst);
+    }
+}
+
+impl<TAddr> RateLimiter<TAddr> {
+    pub fn new(limits: RateLimits) -> RateLimiter<TAddr> {
+        let now = Instant::now();
+        let global_burst = limits.global.map_or(0.0, |l| l.burst);
+        RateLimiter {
+            limits,
+            global: Bucket {
+                tokens: global_burst,
+                updated_at: now,
+            },
+            sources: HashMap::new(),
+            purged_at: now,
+            dropped: 0,
+        }
+    }
+
+    /// Current limits.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Number of requests dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl<TAddr> RateLimiter<TAddr>
+where
+    TAddr: Hash + Eq + Clone,
+{
+    /// Account for a request from the address, returns false if over limit.
+    pub fn check(&mut self, address: &TAddr) -> bool {
+        let now = Instant::now();
+        if let Some(limit) = self.limits.per_source {
+            if !self.sources.contains_key(address) {
+                if self.sources.len() >= MAX_TRACKED_SOURCES
+                    && now.duration_since(self.purged_at)
+                        >= Duration::from_millis(PURGE_INTERVAL_MS)
+                {
+                    self.forget_idle(&limit, now);
+                    self.purged_at = now;
+                }
+                if self.sources.len() >= MAX_TRACKED_SOURCES {
+                    // Forgetting a busy source would give it a fresh bucket
+                    self.dropped += 1;
+                    return false;
+                }
+                self.sources
+                    .insert(address.clone(), Bucket::new(&limit, now));
+            }
+            if !self.sources.get_mut(address).unwrap().take(&limit, now) {
+                self.dropped += 1;
+                return false;
+            }
+        }
+        if let Some(ref limit) = self.limits.global {
+            if !self.global.take(limit, now) {
+                // Not the source's fault, do not charge it
+                if let Some(ref source_limit) = self.limits.per_source {
+                    self.sources.get_mut(address).unwrap().refund(source_limit);
+                }
+                self.dropped += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    fn forget_idle(&mut self, limit: &RateLimit, now: Instant) {
+        self.sources.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, RateLimiter, RateLimits, MAX_TRACKED_SOURCES};
+
+    static SLOW: RateLimit = RateLimit {
+        rate: 0.001,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn test_no_limits() {
+        let mut limiter = RateLimiter::new(RateLimits::default());
+        for _ in 0..1000 {
+            assert!(limiter.check(&1));
+        }
+        assert_eq!(0, limiter.dropped());
+    }
+
+    #[test]
+    fn test_per_source() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            per_source: Some(SLOW),
+            global: None,
+        });
+        for _ in 0..3 {
+            assert!(limiter.check(&1));
+        }
+        assert!(!limiter.check(&1));
+        assert!(limiter.check(&2));
+        assert!(!limiter.check(&1));
+        assert_eq!(2, limiter.dropped());
+    }
+
+    #[test]
+    fn test_global() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            per_source: None,
+            global: Some(SLOW),
+        });
+        assert!(limiter.check(&1));
+        assert!(limiter.check(&2));
+        assert!(limiter.check(&3));
+        assert!(!limiter.check(&4));
+        assert_eq!(1, limiter.dropped());
+    }
+
+    #[test]
+    fn test_global_does_not_charge_source() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            per_source: Some(SLOW),
+            global: Some(RateLimit {
+                rate: 0.001,
+                burst: 1.0,
+            }),
+        });
+        assert!(limiter.check(&1));
+        assert!(!limiter.check(&1));
+        assert!(!limiter.check(&1));
+        let tokens = limiter.sources[&1].tokens;
+        assert!(tokens > 1.9 && tokens < 2.1);
+    }
+
+    #[test]
+    fn test_max_tracked_sources() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            per_source: Some(SLOW),
+            global: None,
+        });
+        for address in 0..MAX_TRACKED_SOURCES {
+            assert!(limiter.check(&address));
+        }
+        // None of the sources is idle, so none of them can be forgotten
+        limiter.purged_at = Instant::now() - Duration::from_secs(10);
+        assert!(!limiter.check(&MAX_TRACKED_SOURCES));
+        assert_eq!(MAX_TRACKED_SOURCES, limiter.sources.len());
+        assert!(!limiter.sources.contains_key(&MAX_TRACKED_SOURCES));
+        assert!(limiter.check(&0));
+        assert_eq!(1, limiter.dropped());
+    }
+
+    #[test]
+    fn test_idle_sources_forgotten() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            per_source: Some(RateLimit {
+                rate: 1e9,
+                burst: 1.0,
+            }),
+            global: None,
+        });
+        for address in 0..MAX_TRACKED_SOURCES {
+            assert!(limiter.check(&address));
+        }
+        ::std::thread::sleep(Duration::from_millis(1));
+        // Not looking for idle sources too often
+        assert!(!limiter.check(&MAX_TRACKED_SOURCES));
+        limiter.purged_at = Instant::now() - Duration::from_secs(10);
+        assert!(limiter.check(&MAX_TRACKED_SOURCES));
+        assert_eq!(1, limiter.sources.len());
+    }
+
+    #[test]
+    fn test_refill() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            per_source: Some(RateLimit {
+                rate: 1e9,
+                burst: 1.0,
+            }),
+            global: None,
+        });
+        assert!(limiter.check(&1));
+        ::std::thread::sleep(::std::time::Duration::from_millis(1));
+        assert!(limiter.check(&1));
+    }
+}
@@ -0,0 +1,580 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serde helpers for IDs and other binary blobs.
+//!
+//! Blobs are hex strings in human-readable formats and raw bytes in
+//! binary ones.
+//!
+//! `RequestPayload` is decoded manually to turn unknown methods into
+//! `RequestPayload::Unknown`. Their arguments can only be skipped in
+//! self-describing formats, binary ones fail on unknown methods.
+
+use std::fmt;
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serializer};
+
+use super::compact::CompactId;
+
+fn serialize_bytes<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    if s.is_human_readable() {
+        s.serialize_str(&bytes.to_hex())
+    } else {
+        s.serialize_bytes(bytes)
+    }
+}
+
+fn deserialize_bytes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    if d.is_human_readable() {
+        d.deserialize_str(HexVisitor)
+    } else {
+        d.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+struct HexVisitor;
+
+impl<'de> Visitor<'de> for HexVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a hex string")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<u8>, E> {
+        value
+            .from_hex()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(value.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(value)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut result = Vec::new();
+        while let Some(byte) = seq.next_element()? {
+            result.push(byte);
+        }
+        Ok(result)
+    }
+}
+
+// ID deserialized with `id::deserialize`
+struct OwnedId<TId>(TId);
+
+impl<'de, TId: CompactId> Deserialize<'de> for OwnedId<TId> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        id::deserialize(d).map(OwnedId)
+    }
+}
+
+/// IDs implementing `compact::CompactId`.
+pub mod id {
+    use serde::de::Error;
+    use serde::{Deserializer, Serializer};
+
+    use super::super::compact::CompactId;
+
+    pub fn serialize<TId: CompactId, S: Serializer>(id: &TId, s: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::new();
+        id.write_bytes(&mut bytes);
+        super::serialize_bytes(&bytes, s)
+    }
+
+    pub fn deserialize<'de, TId: CompactId, D: Deserializer<'de>>(d: D) -> Result<TId, D::Error> {
+        let bytes = super::deserialize_bytes(d)?;
+        TId::from_bytes(&bytes).ok_or_else(|| D::Error::invalid_length(bytes.len(), &"an ID"))
+    }
+}
+
+/// Lists of IDs implementing `compact::CompactId`.
+pub mod ids {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+    use super::super::compact::CompactId;
+    use super::OwnedId;
+
+    struct Id<'a, TId>(&'a TId);
+
+    impl<'a, TId: CompactId> Serialize for Id<'a, TId> {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            super::id::serialize(self.0, s)
+        }
+    }
+
+    pub fn serialize<TId: CompactId, S: Serializer>(ids: &[TId], s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(ids.len()))?;
+        for id in ids {
+            seq.serialize_element(&Id(id))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, TId: CompactId, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<TId>, D::Error> {
+        let ids: Vec<OwnedId<TId>> = Vec::deserialize(d)?;
+        Ok(ids.into_iter().map(|id| id.0).collect())
+    }
+}
+
+/// Byte arrays of fixed size.
+pub mod array {
+    use serde::de::Error;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        array: &[u8; N],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        super::serialize_bytes(array, s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        d: D,
+    ) -> Result<[u8; N], D::Error> {
+        let bytes = super::deserialize_bytes(d)?;
+        if bytes.len() != N {
+            return Err(D::Error::invalid_length(
+                bytes.len(),
+                &"a fixed number of bytes",
+            ));
+        }
+        let mut array = [0u8; N];
+        array.copy_from_slice(&bytes);
+        Ok(array)
+    }
+}
+
+mod payload {
+    use std::convert::TryFrom;
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    use serde::de::value::MapAccessDeserializer;
+    use serde::de::{
+        self, Deserialize, Deserializer, EnumAccess, IgnoredAny, IntoDeserializer, MapAccess,
+        SeqAccess, VariantAccess, Visitor,
+    };
+
+    use super::super::compact::CompactId;
+    use super::super::protocol::{RequestPayload, Token};
+    use super::OwnedId;
+
+    static METHODS: &[&str] = &[
+        "Ping",
+        "FindNode",
+        "FindValue",
+        "Store",
+        "AddProvider",
+        "GetProviders",
+        "SampleKeys",
+        "Unknown",
+    ];
+
+    // Method name, or its index in binary formats
+    struct Method(String);
+
+    impl<'de> Deserialize<'de> for Method {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            d.deserialize_identifier(MethodVisitor)
+        }
+    }
+
+    struct MethodVisitor;
+
+    impl<'de> Visitor<'de> for MethodVisitor {
+        type Value = Method;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a method name")
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Method, E> {
+            let name = usize::try_from(value)
+                .ok()
+                .and_then(|index| METHODS.get(index))
+                .map_or_else(|| value.to_string(), |name| name.to_string());
+            Ok(Method(name))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Method, E> {
+            Ok(Method(value.to_string()))
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Method, E> {
+            Ok(Method(String::from_utf8_lossy(value).into_owned()))
+        }
+    }
+
+    struct PayloadVisitor<TId, TValue>(PhantomData<(TId, TValue)>);
+
+    impl<'de, TId, TValue> Visitor<'de> for PayloadVisitor<TId, TValue>
+    where
+        TId: CompactId,
+        TValue: Deserialize<'de>,
+    {
+        type Value = RequestPayload<TId, TValue>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a request payload")
+        }
+
+        // Methods without arguments in self-describing formats
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            if METHODS.contains(&value) {
+                self.visit_enum(value.into_deserializer())
+            } else {
+                Ok(RequestPayload::Unknown(value.to_string()))
+            }
+        }
+
+        // Methods with arguments in self-describing formats
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            self.visit_enum(MapAccessDeserializer::new(map))
+        }
+
+        fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+            let (Method(method), variant) = data.variant()?;
+            let payload = match method.as_str() {
+                "Ping" => {
+                    variant.unit_variant()?;
+                    RequestPayload::Ping
+                }
+                "FindNode" => RequestPayload::FindNode(variant.newtype_variant::<OwnedId<_>>()?.0),
+                "FindValue" => {
+                    RequestPayload::FindValue(variant.newtype_variant::<OwnedId<_>>()?.0)
+                }
+                "Store" => variant.tuple_variant(3, StoreVisitor(PhantomData))?,
+                "AddProvider" => variant.tuple_variant(2, AddProviderVisitor(PhantomData))?,
+                "GetProviders" => {
+                    RequestPayload::GetProviders(variant.newtype_variant::<OwnedId<_>>()?.0)
+                }
+                "SampleKeys" => {
+                    RequestPayload::SampleKeys(variant.newtype_variant::<OwnedId<_>>()?.0)
+                }
+                "Unknown" => RequestPayload::Unknown(variant.newtype_variant()?),
+                _ => {
+                    variant.newtype_variant::<IgnoredAny>()?;
+                    RequestPayload::Unknown(method)
+                }
+            };
+            Ok(payload)
+        }
+    }
+
+    struct StoreVisitor<TId, TValue>(PhantomData<(TId, TValue)>);
+
+    impl<'de, TId, TValue> Visitor<'de> for StoreVisitor<TId, TValue>
+    where
+        TId: CompactId,
+        TValue: Deserialize<'de>,
+    {
+        type Value = RequestPayload<TId, TValue>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an ID, a value and a token")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let id: OwnedId<TId> = next(&mut seq, 0, &self)?;
+            let value = next(&mut seq, 1, &self)?;
+            let token = next(&mut seq, 2, &self)?;
+            Ok(RequestPayload::Store(id.0, value, token))
+        }
+    }
+
+    struct AddProviderVisitor<TId, TValue>(PhantomData<(TId, TValue)>);
+
+    impl<'de, TId, TValue> Visitor<'de> for AddProviderVisitor<TId, TValue>
+    where
+        TId: CompactId,
+    {
+        type Value = RequestPayload<TId, TValue>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an ID and a token")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let id: OwnedId<TId> = next(&mut seq, 0, &self)?;
+            let token: Token = next(&mut seq, 1, &self)?;
+            Ok(RequestPayload::AddProvider(id.0, token))
+        }
+    }
+
+    fn next<'de, A, T, V>(seq: &mut A, index: usize, visitor: &V) -> Result<T, A::Error>
+    where
+        A: SeqAccess<'de>,
+        T: Deserialize<'de>,
+        V: Visitor<'de>,
+    {
+        seq.next_element()?
+            .ok_or_else(|| de::Error::invalid_length(index, visitor))
+    }
+
+    impl<'de, TId, TValue> Deserialize<'de> for RequestPayload<TId, TValue>
+    where
+        TId: CompactId,
+        TValue: Deserialize<'de>,
+    {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            let visitor = PayloadVisitor(PhantomData);
+            if d.is_human_readable() {
+                d.deserialize_any(visitor)
+            } else {
+                d.deserialize_enum("RequestPayload", METHODS, visitor)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::super::protocol::{
+        KeySample, Request, RequestPayload, Response, ResponsePayload, Signature, Version, Want,
+    };
+    use super::super::service::FindResult;
+    use super::super::Node;
+
+    type TestNode = Node<Vec<u8>, SocketAddr>;
+
+    fn node(id: u8) -> TestNode {
+        Node {
+            id: vec![id, 0xab],
+            address: "127.0.0.1:8008".parse().unwrap(),
+        }
+    }
+
+    fn request() -> Request<Vec<u8>, SocketAddr, String> {
+        Request {
+            caller: node(1),
+            request_id: vec![42],
+            payload: RequestPayload::Store(vec![2], "value".to_string(), vec![1, 2, 3]),
+            version: Version::current(),
+            read_only: false,
+            want: Some(Want::both()),
+            signature: Some(Signature {
+                public_key: [1; 32],
+                signature: [2; 64],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_node_json() {
+        let json = serde_json::to_string(&node(1)).unwrap();
+        assert_eq!(r#"{"address":"127.0.0.1:8008","id":"01ab"}"#, json);
+        let decoded: TestNode = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![1, 0xab], decoded.id);
+
+        let bad = r#"{"address":"127.0.0.1:8008","id":"zz"}"#;
+        assert!(serde_json::from_str::<TestNode>(bad).is_err());
+    }
+
+    #[test]
+    fn test_u64_id_json() {
+        let node = Node {
+            id: 0x0102u64,
+            address: "127.0.0.1:8008".parse::<SocketAddr>().unwrap(),
+        };
+        let json = serde_json::to_string(&node).unwrap();
+        assert!(json.contains(r#""id":"0000000000000102""#));
+        let decoded: Node<u64, SocketAddr> = serde_json::from_str(&json).unwrap();
+        assert_eq!(0x0102, decoded.id);
+
+        // Padding is only needed with serde, rustc-serialize accepts both
+        let encoded = rustc_serialize::json::encode(&node).unwrap();
+        assert!(encoded.contains(r#""id":"102""#));
+        let decoded: Node<u64, SocketAddr> = rustc_serialize::json::decode(&json).unwrap();
+        assert_eq!(0x0102, decoded.id);
+    }
+
+    #[test]
+    fn test_node_bincode() {
+        let data = bincode::serialize(&node(1)).unwrap();
+        // Raw ID bytes with their length
+        assert_eq!(&[2, 0, 0, 0, 0, 0, 0, 0, 1, 0xab], &data[data.len() - 10..]);
+        let decoded: TestNode = bincode::deserialize(&data).unwrap();
+        assert_eq!(vec![1, 0xab], decoded.id);
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        let json = serde_json::to_string(&request()).unwrap();
+        let decoded: Request<Vec<u8>, SocketAddr, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![42], decoded.request_id);
+        assert_eq!(Some(Want::both()), decoded.want);
+        assert_eq!(request().signature, decoded.signature);
+        match decoded.payload {
+            RequestPayload::Store(id, value, token) => {
+                assert_eq!(vec![2], id);
+                assert_eq!("value", value);
+                assert_eq!(vec![1, 2, 3], token);
+            }
+            _ => panic!("wrong payload"),
+        }
+
+        let data = bincode::serialize(&request()).unwrap();
+        let decoded: Request<Vec<u8>, SocketAddr, String> = bincode::deserialize(&data).unwrap();
+        assert_eq!(request().signature, decoded.signature);
+    }
+
+    #[test]
+    fn test_request_payload_unknown() {
+        type TestPayload = RequestPayload<Vec<u8>, String>;
+
+        let json = r#"{
+            "caller": {"address": "127.0.0.1:8008", "id": "01ab"},
+            "request_id": "2a",
+            "payload": {"Frobnicate": ["01", {"depth": [1, 2]}]},
+            "read_only": true
+        }"#;
+        let decoded: Request<Vec<u8>, SocketAddr, String> = serde_json::from_str(json).unwrap();
+        match decoded.payload {
+            RequestPayload::Unknown(method) => assert_eq!("Frobnicate", method),
+            _ => panic!("wrong payload"),
+        }
+        assert!(decoded.read_only);
+
+        match serde_json::from_str::<TestPayload>(r#""Frobnicate""#).unwrap() {
+            RequestPayload::Unknown(method) => assert_eq!("Frobnicate", method),
+            _ => panic!("wrong payload"),
+        }
+        let json = serde_json::to_string(&TestPayload::Unknown("Frobnicate".to_string())).unwrap();
+        match serde_json::from_str::<TestPayload>(&json).unwrap() {
+            RequestPayload::Unknown(method) => assert_eq!("Frobnicate", method),
+            _ => panic!("wrong payload"),
+        }
+
+        // Known methods are still checked
+        assert!(serde_json::from_str::<TestPayload>(r#"{"FindNode": "zz"}"#).is_err());
+        assert!(serde_json::from_str::<TestPayload>(r#""FindNode""#).is_err());
+        match serde_json::from_str::<TestPayload>(r#""Ping""#).unwrap() {
+            RequestPayload::Ping => (),
+            _ => panic!("wrong payload"),
+        }
+
+        // Arguments cannot be skipped in binary formats
+        assert!(bincode::deserialize::<TestPayload>(&[42, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_request_payload_roundtrip() {
+        let payloads: Vec<RequestPayload<Vec<u8>, String>> = vec![
+            RequestPayload::Ping,
+            RequestPayload::FindNode(vec![1]),
+            RequestPayload::FindValue(vec![2]),
+            RequestPayload::AddProvider(vec![3], vec![4, 5]),
+            RequestPayload::GetProviders(vec![6]),
+            RequestPayload::SampleKeys(vec![7]),
+            RequestPayload::Unknown("Frobnicate".to_string()),
+        ];
+        for payload in payloads {
+            let data = bincode::serialize(&payload).unwrap();
+            let decoded: RequestPayload<Vec<u8>, String> = bincode::deserialize(&data).unwrap();
+            assert_eq!(data, bincode::serialize(&decoded).unwrap());
+            let json = serde_json::to_string(&payload).unwrap();
+            let decoded: RequestPayload<Vec<u8>, String> = serde_json::from_str(&json).unwrap();
+            assert_eq!(json, serde_json::to_string(&decoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let response = Response {
+            request: request(),
+            responder: node(3),
+            payload: ResponsePayload::NodesFound(vec![node(4), node(5)]),
+            version: Version::current(),
+            token: Some(vec![7]),
+            observed_address: Some("10.0.0.1:8008".parse().unwrap()),
+            signature: None,
+        };
+        let data = bincode::serialize(&response).unwrap();
+        let decoded: Response<Vec<u8>, SocketAddr, String> = bincode::deserialize(&data).unwrap();
+        assert_eq!(vec![3, 0xab], decoded.responder.id);
+        assert_eq!(response.observed_address, decoded.observed_address);
+        match decoded.payload {
+            ResponsePayload::NodesFound(nodes) => assert_eq!(2, nodes.len()),
+            _ => panic!("wrong payload"),
+        }
+    }
+
+    #[test]
+    fn test_key_sample_json() {
+        let sample = KeySample {
+            keys: vec![vec![1, 2], vec![0xab]],
+            total: 10,
+            interval: Duration::from_secs(60),
+        };
+        let json = serde_json::to_string(&sample).unwrap();
+        assert!(json.contains(r#""keys":["0102","ab"]"#));
+        let decoded: KeySample<Vec<u8>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(sample, decoded);
+    }
+
+    #[test]
+    fn test_find_result() {
+        let result: FindResult<Vec<u8>, SocketAddr, String> =
+            FindResult::ClosestNodes(vec![node(1)]);
+        let json = serde_json::to_string(&result).unwrap();
+        assert_eq!(
+            r#"{"ClosestNodes":[{"address":"127.0.0.1:8008","id":"01ab"}]}"#,
+            json
+        );
+        let result: FindResult<Vec<u8>, SocketAddr, String> =
+            serde_json::from_str(r#"{"Value":["value"]}"#).unwrap();
+        match result {
+            FindResult::Value(values) => assert_eq!(vec!["value"], values),
+            _ => panic!("wrong result"),
+        }
+    }
+
+    #[test]
+    fn test_request_optional_fields() {
+        // Fields missing in older versions get defaults, unknown ones are ignored
+        let json = r#"{
+            "caller": {"address": "127.0.0.1:8008", "id": "01ab"},
+            "request_id": "2a",
+            "payload": "Ping",
+            "future_field": [1, 2, 3]
+        }"#;
+        let decoded: Request<Vec<u8>, SocketAddr, String> = serde_json::from_str(json).unwrap();
+        assert_eq!(Version::default(), decoded.version);
+        assert!(!decoded.read_only);
+        assert!(decoded.want.is_none());
+        assert!(decoded.signature.is_none());
+
+        let json = r#"{"number": 2, "features": ["foo", "bar"]}"#;
+        let version: Version = serde_json::from_str(json).unwrap();
+        assert!(version.supports("bar"));
+        assert!(!version.supports("baz"));
+    }
+}
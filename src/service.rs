@@ -10,21 +10,102 @@
 //! Protocol-agnostic service implementation
 
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::marker;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
-use super::{GenericId, GenericNodeTable, Node};
+use rand;
+use rand::seq::sample_iter;
+
+use super::addrvote::{AddressVotes, DEFAULT_MIN_VOTES};
+use super::banlist::BanList;
+use super::lookup::Lookup;
+use super::multivalue::MultiValueStore;
+use super::protocol::{
+    Authenticator, KeySample, Request, RequestPayload, Response, ResponseError, ResponsePayload,
+    Token, Version, Want, METHOD_UNKNOWN, PROTOCOL_ERROR,
+};
+use super::ratelimit::{RateLimiter, RateLimits};
+use super::token::TokenSecrets;
+use super::{GenericAddress, GenericId, GenericNodeTable, Node, SizeEstimate, UpdateResult};
 
 static MAX_NODE_COUNT: usize = 16;
+static DEFAULT_MAX_FAILURES: usize = 3;
+static ALPHA: usize = 3;
+static DEFAULT_KEY_SAMPLE_SIZE: usize = 20;
+static DEFAULT_KEY_SAMPLE_INTERVAL_SECS: u64 = 60 * 60;
 
 /// Result of the find operations - either data or nodes closest to it.
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "TId: crate::compact::CompactId, TAddr: ::serde::Serialize, \
+                     TData: ::serde::Serialize",
+        deserialize = "TId: crate::compact::CompactId, TAddr: ::serde::Deserialize<'de>, \
+                       TData: ::serde::Deserialize<'de>"
+    ))
+)]
 pub enum FindResult<TId, TAddr, TData> {
-    Value(TData),
+    /// Values found.
+    ///
+    /// Lookups may find several values on nodes with multi-value storage
+    /// enabled, whether or not it is enabled locally.
+    Value(Vec<TData>),
     ClosestNodes(Vec<Node<TId, TAddr>>),
     Nothing,
 }
 
+/// Provider records, with the provider addresses as publishers.
+pub type ProviderStore<TId, TAddr> = MultiValueStore<TId, Node<TId, TAddr>, TAddr>;
+/// Providers of an ID and the nodes closest to it.
+pub type Providers<TId, TAddr> = (Vec<Node<TId, TAddr>>, Vec<Node<TId, TAddr>>);
+/// Sample of keys and nodes closest to the requested ID.
+pub type SampledKeys<TId, TAddr> = (KeySample<TId>, Vec<Node<TId, TAddr>>);
+
+/// Check applied to nodes before inserting them into the node table.
+pub type NodeValidator<TId, TAddr> = Box<dyn Fn(&Node<TId, TAddr>) -> bool + Send + Sync>;
+
+/// Check applied to values before storing them, see `Service::set_value_validator`.
+pub type ValueValidator<TId, TData> =
+    Box<dyn Fn(&TId, &TData, Option<&TData>) -> Result<(), ResponseError> + Send + Sync>;
+
+/// Callback called with the new external address, see `Service::set_address_listener`.
+pub type AddressListener<TAddr> = Box<dyn Fn(&TAddr) + Send + Sync>;
+
+/// Callback called with every event, see `Service::set_event_listener`.
+pub type EventListener<TId, TAddr> = Box<dyn Fn(&Event<TId, TAddr>) + Send + Sync>;
+
+/// Change in the service state or activity, reported to the event listener.
+#[derive(Clone, Debug)]
+pub enum Event<TId, TAddr> {
+    /// Node was added to the node table.
+    NodeAdded(Node<TId, TAddr>),
+    /// Node was removed from the node table: it failed too many RPCs,
+    /// got banned or did not pass the check on clean up.
+    NodeEvicted(Node<TId, TAddr>),
+    /// Node was not added, because its bucket is full; see `Service::clean_up`.
+    BucketFull(Node<TId, TAddr>),
+    /// Value under the ID was stored on request from the node.
+    ValueStored(TId, Node<TId, TAddr>),
+    /// Some values under the ID expired from the multi-value store.
+    ValueExpired(TId),
+    /// Some provider records under the ID expired.
+    ProvidersExpired(TId),
+    /// Request was received from the node, before any checks.
+    RequestReceived(Node<TId, TAddr>),
+    /// Lookup of the target finished.
+    LookupCompleted {
+        target: TId,
+        /// Whether values were found.
+        found_value: bool,
+        /// Number of closest nodes found.
+        nodes: usize,
+    },
+}
+
 /// Handler - implementation of DHT requests.
 pub struct Handler<TId, TAddr, TNodeTable, TData>
 where
@@ -36,7 +117,22 @@ where
     node_id: TId,
     table: Arc<RwLock<TNodeTable>>,
     data: Arc<RwLock<HashMap<TId, TData>>>,
+    multi_values: Option<MultiValueStore<TId, TData, TAddr>>,
+    providers: ProviderStore<TId, TAddr>,
+    key_sample_size: usize,
+    key_sample_interval: Duration,
     clean_needed: bool,
+    validator: Option<NodeValidator<TId, TAddr>>,
+    value_validator: Option<ValueValidator<TId, TData>>,
+    authenticator: Option<Box<dyn Authenticator<TId, TAddr, TData>>>,
+    tokens: TokenSecrets,
+    limiter: RateLimiter<TAddr>,
+    bans: BanList<TId, TAddr>,
+    read_only: bool,
+    version: Version,
+    address_votes: AddressVotes<TAddr, TAddr>,
+    address_listener: Option<AddressListener<TAddr>>,
+    event_listener: Option<EventListener<TId, TAddr>>,
 }
 
 /// Protocol agnostic DHT service.
@@ -55,6 +151,8 @@ where
     node_id: TId,
     table: Arc<RwLock<TNodeTable>>,
     data: Arc<RwLock<HashMap<TId, TData>>>,
+    failures: HashMap<TId, usize>,
+    max_failures: usize,
 }
 
 impl<TId, TAddr, TNodeTable, TData> Service<TId, TAddr, TNodeTable, TData>
@@ -81,22 +179,39 @@ where
             node_id: node_id.clone(),
             table: table.clone(),
             data: data.clone(),
+            multi_values: None,
+            providers: MultiValueStore::new(),
+            key_sample_size: DEFAULT_KEY_SAMPLE_SIZE,
+            key_sample_interval: Duration::from_secs(DEFAULT_KEY_SAMPLE_INTERVAL_SECS),
             clean_needed: false,
+            validator: None,
+            value_validator: None,
+            authenticator: None,
+            tokens: TokenSecrets::new(),
+            limiter: RateLimiter::new(RateLimits::default()),
+            bans: BanList::new(),
+            read_only: false,
+            version: Version::current(),
+            address_votes: AddressVotes::new(DEFAULT_MIN_VOTES),
+            address_listener: None,
+            event_listener: None,
         };
         Service {
-            handler: handler,
-            node_id: node_id,
-            table: table,
-            data: data,
+            handler,
+            node_id,
+            table,
+            data,
+            failures: HashMap::new(),
+            max_failures: DEFAULT_MAX_FAILURES,
         }
     }
 
     /// Get an immutable reference to the node table.
-    pub fn node_table(&self) -> RwLockReadGuard<TNodeTable> {
+    pub fn node_table(&self) -> RwLockReadGuard<'_, TNodeTable> {
         self.table.read().unwrap()
     }
     /// Get a mutable reference to the node table.
-    pub fn node_table_mut(&mut self) -> RwLockWriteGuard<TNodeTable> {
+    pub fn node_table_mut(&mut self) -> RwLockWriteGuard<'_, TNodeTable> {
         self.table.write().unwrap()
     }
     /// Get the current node ID.
@@ -104,13 +219,203 @@ where
         &self.node_id
     }
     /// Get an immutable reference to the data.
-    pub fn stored_data(&self) -> RwLockReadGuard<HashMap<TId, TData>> {
+    pub fn stored_data(&self) -> RwLockReadGuard<'_, HashMap<TId, TData>> {
         self.data.read().unwrap()
     }
     /// Get an immutable reference to the data.
-    pub fn stored_data_mut(&mut self) -> RwLockWriteGuard<HashMap<TId, TData>> {
+    pub fn stored_data_mut(&mut self) -> RwLockWriteGuard<'_, HashMap<TId, TData>> {
         self.data.write().unwrap()
     }
+    /// Store several values per ID, each with its own publisher and expiry.
+    ///
+    /// Values are kept in the given store instead of `stored_data`, and
+    /// find replies carry a random sample of them. Publishers are identified
+    /// by the addresses their store requests came from, as node IDs can be
+    /// claimed by anyone.
+    pub fn enable_multi_value(&mut self, store: MultiValueStore<TId, TData, TAddr>) {
+        self.handler.multi_values = Some(store);
+    }
+    /// Get an immutable reference to the multi-value store, if enabled.
+    pub fn multi_values(&self) -> Option<&MultiValueStore<TId, TData, TAddr>> {
+        self.handler.multi_values.as_ref()
+    }
+    /// Get a mutable reference to the multi-value store, if enabled.
+    pub fn multi_values_mut(&mut self) -> Option<&mut MultiValueStore<TId, TData, TAddr>> {
+        self.handler.multi_values.as_mut()
+    }
+    /// Get an immutable reference to the provider records.
+    ///
+    /// Providers are nodes announcing that they can serve an ID, stored
+    /// under the ID with the address the announcement came from as the
+    /// publisher.
+    pub fn providers(&self) -> &ProviderStore<TId, TAddr> {
+        &self.handler.providers
+    }
+    /// Get a mutable reference to the provider records.
+    pub fn providers_mut(&mut self) -> &mut ProviderStore<TId, TAddr> {
+        &mut self.handler.providers
+    }
+    /// Replace the provider records, e.g. to change their limits.
+    pub fn set_providers(&mut self, store: ProviderStore<TId, TAddr>) {
+        self.handler.providers = store;
+    }
+    /// Get the maximum number of keys in replies to sample requests.
+    pub fn key_sample_size(&self) -> usize {
+        self.handler.key_sample_size
+    }
+    /// Set the maximum number of keys in replies to sample requests (BEP 51).
+    pub fn set_key_sample_size(&mut self, size: usize) {
+        self.handler.key_sample_size = size;
+    }
+    /// Get the interval before asking for a new sample, sent with samples.
+    pub fn key_sample_interval(&self) -> Duration {
+        self.handler.key_sample_interval
+    }
+    /// Set the interval before asking for a new sample, sent with samples.
+    pub fn set_key_sample_interval(&mut self, interval: Duration) {
+        self.handler.key_sample_interval = interval;
+    }
+    /// Get the number of failed RPCs after which a node is removed.
+    pub fn max_failures(&self) -> usize {
+        self.max_failures
+    }
+    /// Set the number of failed RPCs after which a node is removed.
+    pub fn set_max_failures(&mut self, max_failures: usize) {
+        assert!(max_failures > 0);
+        self.max_failures = max_failures;
+    }
+    /// Set a check nodes must pass before being inserted into the node table.
+    ///
+    /// E.g. `bep42::validate_node` for secure node IDs.
+    pub fn set_node_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(&Node<TId, TAddr>) -> bool + Send + Sync + 'static,
+    {
+        self.handler.validator = Some(Box::new(validator));
+    }
+    /// Set a check values must pass before being stored.
+    ///
+    /// Called with the ID, the new value and the value it replaces, if any
+    /// (in multi-value mode - the previous value of the same publisher).
+    /// The error is sent back to the caller. E.g. `bep44::validate_put`
+    /// for BEP 44 items.
+    pub fn set_value_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(&TId, &TData, Option<&TData>) -> Result<(), ResponseError> + Send + Sync + 'static,
+    {
+        self.handler.value_validator = Some(Box::new(validator));
+    }
+    /// Require incoming messages to pass authentication.
+    ///
+    /// E.g. `skademlia::SKademlia` for S/Kademlia signed messages.
+    pub fn set_authenticator<A>(&mut self, authenticator: A)
+    where
+        A: Authenticator<TId, TAddr, TData> + 'static,
+    {
+        self.handler.authenticator = Some(Box::new(authenticator));
+    }
+    /// Limit the rate of incoming requests handled by `Handler::handle_request`.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.handler.limiter = RateLimiter::new(limits);
+    }
+    /// Get the current rate limits.
+    pub fn rate_limits(&self) -> &RateLimits {
+        self.handler.limiter.limits()
+    }
+    /// Number of requests dropped because of rate limits.
+    pub fn rate_limited_count(&self) -> u64 {
+        self.handler.limiter.dropped()
+    }
+    /// Get an immutable reference to the ban list.
+    pub fn ban_list(&self) -> &BanList<TId, TAddr> {
+        &self.handler.bans
+    }
+    /// Get a mutable reference to the ban list.
+    ///
+    /// Banned nodes already in the node table are removed on `clean_up`.
+    pub fn ban_list_mut(&mut self) -> &mut BanList<TId, TAddr> {
+        &mut self.handler.bans
+    }
+    /// Check if the service is in read-only mode.
+    pub fn read_only(&self) -> bool {
+        self.handler.read_only
+    }
+    /// Switch read-only mode (BEP 43).
+    ///
+    /// A read-only node only sends queries: its requests ask other nodes
+    /// not to add it to their node tables, and `Handler::handle_request`
+    /// drops all incoming requests.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.handler.read_only = read_only;
+    }
+    /// Get the version advertised in requests and responses.
+    pub fn version(&self) -> &Version {
+        &self.handler.version
+    }
+    /// Set the version advertised in requests and responses.
+    ///
+    /// E.g. to announce optional features supported on top of the protocol.
+    /// Versions advertised by other nodes are kept in the node table, see
+    /// `GenericNodeTable::version`.
+    pub fn set_version(&mut self, version: Version) {
+        self.handler.version = version;
+    }
+    /// Get the external address agreed on by the majority of responders.
+    ///
+    /// Guessed from `Response::observed_address` of processed responses,
+    /// with one vote per responding host (see `GenericAddress::host`).
+    pub fn external_address(&self) -> Option<&TAddr> {
+        self.handler.address_votes.best()
+    }
+    /// Get the number of votes needed to guess the external address.
+    pub fn min_address_votes(&self) -> usize {
+        self.handler.address_votes.min_votes()
+    }
+    /// Set the number of votes needed to guess the external address.
+    ///
+    /// Resets collected votes.
+    pub fn set_min_address_votes(&mut self, min_votes: usize) {
+        self.handler.address_votes = AddressVotes::new(min_votes);
+    }
+    /// Set a callback to call when the external address guess changes.
+    pub fn set_address_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(&TAddr) + Send + Sync + 'static,
+    {
+        self.handler.address_listener = Some(Box::new(listener));
+    }
+    /// Set a callback to call on every event, e.g. for metrics or logging.
+    ///
+    /// The callback runs synchronously, it should return quickly and must
+    /// not call back into the service.
+    pub fn set_event_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(&Event<TId, TAddr>) + Send + Sync + 'static,
+    {
+        self.handler.event_listener = Some(Box::new(listener));
+    }
+    /// Create an outgoing request with a random request ID.
+    ///
+    /// `caller` is this node as seen by the callee.
+    pub fn new_request(
+        &self,
+        caller: Node<TId, TAddr>,
+        payload: RequestPayload<TId, TData>,
+    ) -> Request<TId, TAddr, TData> {
+        Request {
+            caller,
+            request_id: self.node_table().random_id(),
+            payload,
+            version: self.handler.version.clone(),
+            read_only: self.handler.read_only,
+            want: None,
+            signature: None,
+        }
+    }
+    /// Get a mutable reference to the request handler.
+    pub fn handler_mut(&mut self) -> &mut Handler<TId, TAddr, TNodeTable, TData> {
+        &mut self.handler
+    }
     /// Check if some buckets are full already.
     pub fn clean_needed(&self) -> bool {
         self.handler.clean_needed
@@ -118,30 +423,372 @@ where
 
     /// Try to clean up the table by checking the oldest records.
     ///
+    /// Also drops banned nodes, expired bans, values, provider records and
+    /// failure counts of nodes no longer in the table.
     /// Should be called periodically, especially when clean_needed is true.
     pub fn clean_up<TCheck>(&mut self, mut check: TCheck)
     where
+        TAddr: Hash + Eq,
         TCheck: FnMut(&Node<TId, TAddr>) -> bool,
     {
+        self.handler.bans.purge_expired();
+        let expired = match self.handler.multi_values {
+            Some(ref mut store) => store.purge_expired(),
+            None => Vec::new(),
+        };
+        for id in expired {
+            self.handler.notify(|| Event::ValueExpired(id));
+        }
+        for id in self.handler.providers.purge_expired() {
+            self.handler.notify(|| Event::ProvidersExpired(id));
+        }
+
+        let mut evicted = Vec::new();
         {
-            let mut node_table = self.node_table_mut();
+            let mut node_table = self.table.write().unwrap();
+            let banned: Vec<TId> = node_table
+                .nodes()
+                .filter(|n| self.handler.bans.is_banned(n))
+                .map(|n| n.id.clone())
+                .collect();
+            for id in banned {
+                debug!("Removing banned node {:?}", id);
+                evicted.extend(node_table.remove(&id));
+            }
+            // Forget failures of nodes that left the table
+            self.failures.retain(|id, _| node_table.contains(id));
+
             let oldest = node_table.pop_oldest();
             for node in oldest {
                 if check(&node) {
                     node_table.update(&node);
+                } else {
+                    evicted.push(node);
                 }
             }
         }
+        for node in evicted {
+            self.handler.notify(|| Event::NodeEvicted(node));
+        }
         self.handler.clean_needed = false;
     }
+
+    /// Look up a value or the closest nodes in the network.
+    ///
+    /// Seeds the lookup from the node table; `query` performs a find RPC
+    /// to a node and returns `FindResult::Nothing` on failure.
+    pub fn lookup<F>(&self, id: &TId, query: F) -> FindResult<TId, TAddr, TData>
+    where
+        TAddr: Hash + Eq + Clone,
+        F: FnMut(&Node<TId, TAddr>, &TId) -> FindResult<TId, TAddr, TData>,
+    {
+        self.lookup_disjoint(id, 1, query)
+    }
+    /// Look up using `paths` independent lookups over disjoint sets of nodes.
+    ///
+    /// More robust against malicious nodes returning bogus contacts.
+    pub fn lookup_disjoint<F>(
+        &self,
+        id: &TId,
+        paths: usize,
+        query: F,
+    ) -> FindResult<TId, TAddr, TData>
+    where
+        TAddr: Hash + Eq + Clone,
+        F: FnMut(&Node<TId, TAddr>, &TId) -> FindResult<TId, TAddr, TData>,
+    {
+        let bans = &self.handler.bans;
+        let mut query = query;
+        let result = self
+            .new_lookup(id, paths)
+            .run(|node, id| match query(node, id) {
+                FindResult::ClosestNodes(nodes) => FindResult::ClosestNodes(
+                    nodes.into_iter().filter(|n| !bans.is_banned(n)).collect(),
+                ),
+                other => other,
+            });
+        self.finish_lookup(id, &result);
+        result
+    }
+
+    /// Remember the result of a lookup run manually.
+    ///
+    /// Lookups run with `lookup` and `lookup_disjoint` are recorded
+    /// automatically.
+    pub fn record_lookup(&mut self, id: &TId, result: &FindResult<TId, TAddr, TData>) {
+        self.finish_lookup(id, result);
+    }
+
+    /// Estimate the number of nodes in the network.
+    ///
+    /// Based on the density of IDs in the closest buckets of the node table,
+    /// once one of them is full, and on recent lookup results. `None` if
+    /// nothing is known yet.
+    pub fn estimate_size(&self) -> Option<SizeEstimate> {
+        self.node_table().estimate_size()
+    }
+
+    /// Create a lookup seeded from the node table, for running it manually.
+    ///
+    /// Banned nodes are left out of the seeds, but `Lookup` knows nothing
+    /// about bans: unlike `lookup_disjoint`, callers must remove banned nodes
+    /// (see `ban_list`) from query results before passing them to
+    /// `Lookup::record`.
+    pub fn new_lookup(&self, id: &TId, paths: usize) -> Lookup<TId, TAddr>
+    where
+        TAddr: Hash + Eq + Clone,
+    {
+        let count = MAX_NODE_COUNT * paths;
+        let table = self.node_table();
+        // Node tables may refuse to find our own ID, e.g. when bootstrapping
+        let seeds = if *id == self.node_id {
+            table.find_filtered(id, count, &|_| true)
+        } else {
+            table.find(id, count)
+        };
+        let seeds = seeds
+            .into_iter()
+            .filter(|n| !self.handler.bans.is_banned(n))
+            .collect();
+        let mut lookup = Lookup::new_with_details(id.clone(), seeds, MAX_NODE_COUNT, ALPHA, paths);
+        lookup.exclude(self.node_id.clone());
+        lookup
+    }
+
+    /// Record a failed RPC to the node (e.g. a timeout or a garbage reply).
+    ///
+    /// Once the node fails `max_failures` times in a row, it is removed from
+    /// the node table. Returns true if the node was removed. Failures of
+    /// nodes not in the table are not counted.
+    pub fn report_failure(&mut self, node: &Node<TId, TAddr>) -> bool {
+        if !self.node_table().contains(&node.id) {
+            self.failures.remove(&node.id);
+            return false;
+        }
+        let count = {
+            let count = self.failures.entry(node.id.clone()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if count < self.max_failures {
+            debug!("Node {:?} failed {} time(s)", node.id, count);
+            return false;
+        }
+
+        self.failures.remove(&node.id);
+        debug!("Removing node {:?} after {} failures", node.id, count);
+        let removed = self.node_table_mut().remove(&node.id);
+        match removed {
+            Some(node) => {
+                self.handler.notify(|| Event::NodeEvicted(node));
+                true
+            }
+            None => false,
+        }
+    }
+    /// Record a successful RPC to the node, resetting its failure count.
+    pub fn report_success(&mut self, node: &Node<TId, TAddr>) {
+        self.failures.remove(&node.id);
+    }
+
+    fn finish_lookup(&self, id: &TId, result: &FindResult<TId, TAddr, TData>) {
+        let (found_value, nodes) = match *result {
+            FindResult::Value(..) => (true, 0),
+            FindResult::ClosestNodes(ref nodes) => {
+                self.table.write().unwrap().record_lookup(id, nodes);
+                (false, nodes.len())
+            }
+            FindResult::Nothing => (false, 0),
+        };
+        self.handler.notify(|| Event::LookupCompleted {
+            target: id.clone(),
+            found_value,
+            nodes,
+        });
+    }
+}
+
+impl<TId, TAddr, TNodeTable, TData> Handler<TId, TAddr, TNodeTable, TData>
+where
+    TId: GenericId,
+    TNodeTable: GenericNodeTable<TId, TAddr>,
+    TData: Send + Sync + Clone,
+{
+    // Events are only built if there is a listener
+    fn notify<F>(&self, event: F)
+    where
+        F: FnOnce() -> Event<TId, TAddr>,
+    {
+        if let Some(ref listener) = self.event_listener {
+            listener(&event());
+        }
+    }
 }
 
 impl<TId, TAddr, TNodeTable, TData> Handler<TId, TAddr, TNodeTable, TData>
 where
     TId: GenericId,
+    TAddr: GenericAddress,
     TNodeTable: GenericNodeTable<TId, TAddr>,
     TData: Send + Sync + Clone,
 {
+    /// Process an incoming request.
+    ///
+    /// `source` is the address the transport received the request from,
+    /// `responder` is this node as seen by the caller. Returns the response
+    /// to send, possibly with an error, or `None` if the request must be
+    /// ignored (in read-only mode, from a banned node or over the rate limit).
+    pub fn handle_request(
+        &mut self,
+        request: Request<TId, TAddr, TData>,
+        source: &TAddr,
+        responder: Node<TId, TAddr>,
+    ) -> Option<Response<TId, TAddr, TData>> {
+        self.notify(|| Event::RequestReceived(request.caller.clone()));
+        if self.read_only {
+            debug!(
+                "Dropping request from {:?}: read-only mode",
+                request.caller.id
+            );
+            return None;
+        }
+        if self.bans.is_banned(&request.caller) || self.bans.is_address_banned(source) {
+            debug!("Dropping request from banned node {:?}", request.caller.id);
+            return None;
+        }
+        if !self.check_rate_limit(source) {
+            debug!(
+                "Dropping request from {:?}: rate limited",
+                request.caller.id
+            );
+            return None;
+        }
+        if let Some(ref authenticator) = self.authenticator {
+            if !authenticator.verify_request(&request) {
+                debug!(
+                    "Rejecting request from {:?}: authentication failed",
+                    request.caller.id
+                );
+                let error = ResponseError::new(PROTOCOL_ERROR, "invalid signature");
+                return Some(self.respond(
+                    request,
+                    source,
+                    responder,
+                    ResponsePayload::Error(error),
+                    None,
+                ));
+            }
+        }
+
+        let mut token = None;
+        let payload = {
+            let sender = &request.caller;
+            let payload = match request.payload {
+                RequestPayload::Ping => ResponsePayload::NoResult,
+                RequestPayload::FindNode(ref id) => {
+                    token = Some(self.token_for(source));
+                    ResponsePayload::NodesFound(self.find_node(source, request.want, id))
+                }
+                RequestPayload::FindValue(ref id) => {
+                    token = Some(self.token_for(source));
+                    match self.find_value(source, request.want, id) {
+                        // Callers before version 2 expect a single value
+                        FindResult::Value(mut values) if request.version.number < 2 => {
+                            values.truncate(1);
+                            ResponsePayload::ValueFound(values)
+                        }
+                        FindResult::Value(values) => ResponsePayload::ValueFound(values),
+                        FindResult::ClosestNodes(nodes) => ResponsePayload::NodesFound(nodes),
+                        FindResult::Nothing => ResponsePayload::NoResult,
+                    }
+                }
+                RequestPayload::Store(ref id, ref value, ref token) => {
+                    match self.store(sender, source, id, value.clone(), token) {
+                        Ok(()) => ResponsePayload::NoResult,
+                        Err(error) => ResponsePayload::Error(error),
+                    }
+                }
+                RequestPayload::AddProvider(ref id, ref token) => {
+                    match self.add_provider(sender, source, id, token) {
+                        Ok(()) => ResponsePayload::NoResult,
+                        Err(error) => ResponsePayload::Error(error),
+                    }
+                }
+                RequestPayload::GetProviders(ref id) => {
+                    token = Some(self.token_for(source));
+                    let (providers, nodes) = self.get_providers(source, request.want, id);
+                    ResponsePayload::ProvidersFound(providers, nodes)
+                }
+                RequestPayload::SampleKeys(ref id) => {
+                    let nodes = self.find_node(source, request.want, id);
+                    ResponsePayload::KeysSampled(self.sample_keys(), nodes)
+                }
+                RequestPayload::Unknown(ref method) => {
+                    debug!("Unknown method {:?} from {:?}", method, sender.id);
+                    ResponsePayload::Error(ResponseError::new(METHOD_UNKNOWN, "method unknown"))
+                }
+            };
+            // Read-only nodes do not answer queries, so are of no use in the table
+            let failed = matches!(payload, ResponsePayload::Error(..));
+            if !request.read_only && !failed {
+                // Only the address the request came from is known to be reachable
+                let sender = Node {
+                    id: sender.id.clone(),
+                    address: source.clone(),
+                };
+                self.update(&sender);
+                self.record_version(&sender, &request.version);
+            }
+            payload
+        };
+        Some(self.respond(request, source, responder, payload, token))
+    }
+    /// Process an incoming response.
+    ///
+    /// `source` is the address the transport received the response from.
+    /// Remembers the responder with that address and returns true, if the
+    /// response is authentic.
+    pub fn handle_response(
+        &mut self,
+        response: &Response<TId, TAddr, TData>,
+        source: &TAddr,
+    ) -> bool {
+        if self.bans.is_banned(&response.responder) || self.bans.is_address_banned(source) {
+            debug!(
+                "Dropping response from banned node {:?}",
+                response.responder.id
+            );
+            return false;
+        }
+        if let Some(ref authenticator) = self.authenticator {
+            if !authenticator.verify_response(response) {
+                debug!(
+                    "Dropping response from {:?}: authentication failed",
+                    response.responder.id
+                );
+                return false;
+            }
+        }
+        if let Some(ref address) = response.observed_address {
+            // One vote per host, node IDs and ports are cheap
+            if self.address_votes.add(source.host(), address.clone()) {
+                debug!(
+                    "External address changed, voted by {:?}",
+                    response.responder.id
+                );
+                if let Some(ref listener) = self.address_listener {
+                    listener(address);
+                }
+            }
+        }
+        let responder = Node {
+            id: response.responder.id.clone(),
+            address: source.clone(),
+        };
+        self.update(&responder);
+        self.record_version(&responder, &response.version);
+        true
+    }
     /// Process the ping request.
     ///
     /// Essentially remembers the incoming node and returns true.
@@ -151,7 +798,7 @@ where
     }
     /// Process the find request.
     pub fn on_find_node(&mut self, sender: &Node<TId, TAddr>, id: &TId) -> Vec<Node<TId, TAddr>> {
-        let res = self.table.read().unwrap().find(&id, MAX_NODE_COUNT);
+        let res = self.find_node(&sender.address, None, id);
         self.update(sender);
         res
     }
@@ -162,22 +809,263 @@ where
         id: &TId,
     ) -> FindResult<TId, TAddr, TData> {
         self.update(sender);
-        let data = self.data.read().unwrap();
+        self.find_value(&sender.address, None, id)
+    }
+    /// Store a value, if the sender presents a valid token.
+    ///
+    /// The token is checked against `sender.address`, which must be the
+    /// address the request came from. Returns false if the store was
+    /// rejected, because of the token or by the value validator.
+    pub fn on_store(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        id: &TId,
+        value: TData,
+        token: &[u8],
+    ) -> bool {
+        if self
+            .store(sender, &sender.address, id, value, token)
+            .is_err()
+        {
+            return false;
+        }
+        self.update(sender);
+        true
+    }
+    /// Remember the sender as a provider of the ID, if it presents a valid token.
+    ///
+    /// `sender.address` must be the address the request came from, it is
+    /// both checked against the token and handed out to others. Returns
+    /// false if the token is invalid.
+    pub fn on_add_provider(&mut self, sender: &Node<TId, TAddr>, id: &TId, token: &[u8]) -> bool {
+        if self
+            .add_provider(sender, &sender.address, id, token)
+            .is_err()
+        {
+            return false;
+        }
+        self.update(sender);
+        true
+    }
+    /// Find providers of the ID and the closest nodes.
+    pub fn on_get_providers(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        id: &TId,
+    ) -> Providers<TId, TAddr> {
+        self.update(sender);
+        self.get_providers(&sender.address, None, id)
+    }
+    /// Sample stored keys and find the nodes closest to the ID (BEP 51).
+    pub fn on_sample_keys(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        id: &TId,
+    ) -> SampledKeys<TId, TAddr> {
+        self.update(sender);
+        (
+            self.sample_keys(),
+            self.find_node(&sender.address, None, id),
+        )
+    }
+    /// Account for a request from the address, returns false if over limit.
+    ///
+    /// Called by `handle_request`, use it when calling `on_*` directly
+    /// with the address the request came from.
+    pub fn check_rate_limit(&mut self, address: &TAddr) -> bool {
+        self.limiter.check(&address.host())
+    }
+    /// Generate a write token for the address, to send with find replies.
+    ///
+    /// Pass the address the request came from, not the one the caller claims.
+    pub fn token_for(&mut self, address: &TAddr) -> Token {
+        self.tokens.generate(address)
+    }
+
+    // Nodes for a reply to a request from the source address, only of
+    // families in `want` or of the family of the source (BEP 32)
+    fn find_node(&self, source: &TAddr, want: Option<Want>, id: &TId) -> Vec<Node<TId, TAddr>> {
+        let want = want.unwrap_or_else(|| source.family());
+        // Node tables may refuse to find our own ID, so always filter
         let table = self.table.read().unwrap();
-        let res = match data.get(&id) {
-            Some(value) => FindResult::Value(value.clone()),
-            None => FindResult::ClosestNodes(table.find(&id, MAX_NODE_COUNT)),
+        let mut result = Vec::new();
+        if want.ipv4 {
+            result.extend(table.find_filtered(id, MAX_NODE_COUNT, &|n| n.address.family().ipv4));
+        }
+        if want.ipv6 {
+            // Skip nodes reachable over IPv4 given above
+            result.extend(table.find_filtered(id, MAX_NODE_COUNT, &|n| {
+                let family = n.address.family();
+                family.ipv6 && !(want.ipv4 && family.ipv4)
+            }));
+        }
+        result
+    }
+
+    fn find_value(
+        &self,
+        source: &TAddr,
+        want: Option<Want>,
+        id: &TId,
+    ) -> FindResult<TId, TAddr, TData> {
+        let values = match self.multi_values {
+            Some(ref store) => store.sample(id),
+            None => self
+                .data
+                .read()
+                .unwrap()
+                .get(id)
+                .cloned()
+                .into_iter()
+                .collect(),
         };
-        res
+        if values.is_empty() {
+            FindResult::ClosestNodes(self.find_node(source, want, id))
+        } else {
+            FindResult::Value(values)
+        }
+    }
+
+    fn store(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        source: &TAddr,
+        id: &TId,
+        value: TData,
+        token: &[u8],
+    ) -> Result<(), ResponseError> {
+        if !self.tokens.verify(source, token) {
+            debug!("Rejecting store from {:?}: invalid token", sender.id);
+            return Err(ResponseError::new(PROTOCOL_ERROR, "invalid token"));
+        }
+        let validator = &self.value_validator;
+        let validate = |current: Option<&TData>| match *validator {
+            Some(ref validator) => validator(id, &value, current).map_err(|error| {
+                debug!("Rejecting store from {:?}: {:?}", sender.id, error);
+                error
+            }),
+            None => Ok(()),
+        };
+        match self.multi_values {
+            Some(ref mut store) => {
+                let current = store
+                    .get(id)
+                    .into_iter()
+                    .find(|v| v.publisher == *source)
+                    .map(|v| &v.value);
+                validate(current)?;
+                store.insert(id.clone(), source.clone(), value);
+            }
+            None => {
+                let mut data = self.data.write().unwrap();
+                validate(data.get(id))?;
+                data.insert(id.clone(), value);
+            }
+        }
+        self.notify(|| Event::ValueStored(id.clone(), sender.clone()));
+        Ok(())
+    }
+
+    fn add_provider(
+        &mut self,
+        sender: &Node<TId, TAddr>,
+        source: &TAddr,
+        id: &TId,
+        token: &[u8],
+    ) -> Result<(), ResponseError> {
+        if !self.tokens.verify(source, token) {
+            debug!("Rejecting provider {:?}: invalid token", sender.id);
+            return Err(ResponseError::new(PROTOCOL_ERROR, "invalid token"));
+        }
+        // Others will contact the provider at the address we saw it at
+        let provider = Node {
+            id: sender.id.clone(),
+            address: source.clone(),
+        };
+        self.providers.insert(id.clone(), source.clone(), provider);
+        Ok(())
+    }
+
+    fn get_providers(&self, source: &TAddr, want: Option<Want>, id: &TId) -> Providers<TId, TAddr> {
+        let providers = self
+            .providers
+            .sample(id)
+            .into_iter()
+            .filter(|n| !self.bans.is_banned(n))
+            .collect();
+        (providers, self.find_node(source, want, id))
+    }
+
+    fn sample_keys(&self) -> KeySample<TId> {
+        let mut rng = rand::thread_rng();
+        let data = self.data.read().unwrap();
+        let (keys, total) = match self.multi_values {
+            Some(ref store) => (
+                sample_iter(&mut rng, store.keys(), self.key_sample_size),
+                store.keys().len(),
+            ),
+            None => (
+                sample_iter(&mut rng, data.keys(), self.key_sample_size),
+                data.len(),
+            ),
+        };
+        // Not enough keys to fill the sample is not an error
+        let keys = keys.unwrap_or_else(|keys| keys);
+        KeySample {
+            keys: keys.into_iter().cloned().collect(),
+            total,
+            interval: self.key_sample_interval,
+        }
+    }
+
+    fn respond(
+        &self,
+        request: Request<TId, TAddr, TData>,
+        source: &TAddr,
+        responder: Node<TId, TAddr>,
+        payload: ResponsePayload<TId, TAddr, TData>,
+        token: Option<Token>,
+    ) -> Response<TId, TAddr, TData> {
+        // The caller's own idea of its address proves nothing
+        let observed_address = Some(source.clone());
+        Response {
+            request,
+            responder,
+            payload,
+            version: self.version.clone(),
+            token,
+            observed_address,
+            signature: None,
+        }
+    }
+
+    fn record_version(&mut self, node: &Node<TId, TAddr>, version: &Version) {
+        if node.id != self.node_id {
+            let mut table = self.table.write().unwrap();
+            table.set_version(&node.id, version.bounded());
+        }
     }
 
     fn update(&mut self, node: &Node<TId, TAddr>) {
-        if node.id == self.node_id {
+        if node.id == self.node_id || self.bans.is_banned(node) {
             return;
         }
+        if let Some(ref validator) = self.validator {
+            if !validator(node) {
+                debug!("Node {:?} failed validation, not adding", node.id);
+                return;
+            }
+        }
 
-        if !self.table.write().unwrap().update(&node) {
-            self.clean_needed = true;
+        let result = self.table.write().unwrap().update(node);
+        match result {
+            UpdateResult::Added => self.notify(|| Event::NodeAdded(node.clone())),
+            UpdateResult::Full => {
+                self.clean_needed = true;
+                self.notify(|| Event::BucketFull(node.clone()));
+            }
+            UpdateResult::Rejected => debug!("Node {:?} rejected by node table", node.id),
+            UpdateResult::Updated => {}
         }
     }
 }
@@ -185,11 +1073,19 @@ where
 #[cfg(test)]
 pub mod test {
     use super::super::utils::test;
-    use super::super::{GenericNodeTable, Node};
+    use super::super::{
+        BucketStats, GenericNodeTable, KNodeTable, Node, RateLimit, RateLimits, UpdateResult,
+    };
     use std::net;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
     type TestsIdType = test::IdType;
 
-    use super::{FindResult, Service};
+    use super::super::protocol::{
+        Request, RequestPayload, Response, ResponsePayload, Version, MAX_FEATURES, METHOD_UNKNOWN,
+        PROTOCOL_ERROR,
+    };
+    use super::{Event, FindResult, Service};
 
     struct DummyNodeTable {
         pub node: Option<Node<TestsIdType, net::SocketAddr>>,
@@ -200,12 +1096,12 @@ pub mod test {
             test::make_id(42)
         }
 
-        fn update(&mut self, node: &Node<TestsIdType, net::SocketAddr>) -> bool {
+        fn update(&mut self, node: &Node<TestsIdType, net::SocketAddr>) -> UpdateResult {
             match self.node {
-                Some(..) => false,
+                Some(..) => UpdateResult::Full,
                 None => {
                     self.node = Some(node.clone());
-                    true
+                    UpdateResult::Added
                 }
             }
         }
@@ -232,6 +1128,35 @@ pub mod test {
             self.node = None;
             result
         }
+
+        fn remove(&mut self, id: &TestsIdType) -> Option<Node<TestsIdType, net::SocketAddr>> {
+            if self.node.as_ref().is_some_and(|n| n.id == *id) {
+                self.node.take()
+            } else {
+                None
+            }
+        }
+
+        fn nodes<'a>(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = &'a Node<TestsIdType, net::SocketAddr>> + 'a>
+        where
+            TestsIdType: 'a,
+        {
+            Box::new(self.node.iter())
+        }
+
+        fn bucket_for(&self, _id: &TestsIdType) -> Option<usize> {
+            Some(0)
+        }
+
+        fn bucket_stats(&self) -> Vec<BucketStats> {
+            vec![BucketStats {
+                index: 0,
+                len: self.len(),
+                capacity: 1,
+            }]
+        }
     }
 
     #[test]
@@ -256,7 +1181,7 @@ pub mod test {
         assert!(svc.handler.on_find_node(&node, &node.id).is_empty());
         let result = svc.handler.on_find_node(&node, &node.id);
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id)
+        assert_eq!(test::make_id(43), result[0].id)
     }
 
     #[test]
@@ -282,7 +1207,7 @@ pub mod test {
 
         let mut result = svc.handler.on_find_node(&node, &node.id);
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id);
+        assert_eq!(test::make_id(43), result[0].id);
 
         let mut flag = false;
         svc.clean_up(|node| {
@@ -295,7 +1220,7 @@ pub mod test {
 
         result = svc.handler.on_find_node(&node, &node.id);
         assert_eq!(1, result.len());
-        assert_eq!(test::make_id(43), result.get(0).unwrap().id);
+        assert_eq!(test::make_id(43), result[0].id);
 
         flag = false;
         svc.clean_up(|node| {
@@ -324,7 +1249,7 @@ pub mod test {
         {
             let res1 = svc.handler.on_find_value(&node, &id1);
             match res1 {
-                FindResult::Value(value) => assert_eq!("foobar", value),
+                FindResult::Value(values) => assert_eq!(vec!["foobar"], values),
                 _ => panic!("wrong result {:?}", res1),
             }
         }
@@ -337,4 +1262,1039 @@ pub mod test {
             }
         }
     }
+
+    // Only implements the required methods
+    struct MinimalNodeTable {
+        nodes: Vec<Node<TestsIdType, net::SocketAddr>>,
+    }
+
+    impl GenericNodeTable<TestsIdType, net::SocketAddr> for MinimalNodeTable {
+        fn random_id(&self) -> TestsIdType {
+            test::make_id(42)
+        }
+        fn update(&mut self, node: &Node<TestsIdType, net::SocketAddr>) -> UpdateResult {
+            self.nodes.retain(|n| n.id != node.id);
+            self.nodes.push(node.clone());
+            UpdateResult::Added
+        }
+        fn find(&self, id: &TestsIdType, count: usize) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            self.find_filtered(id, count, &|_| true)
+        }
+        fn pop_oldest(&mut self) -> Vec<Node<TestsIdType, net::SocketAddr>> {
+            vec![]
+        }
+        fn remove(&mut self, id: &TestsIdType) -> Option<Node<TestsIdType, net::SocketAddr>> {
+            let index = self.nodes.iter().position(|n| n.id == *id)?;
+            Some(self.nodes.remove(index))
+        }
+        fn nodes<'a>(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = &'a Node<TestsIdType, net::SocketAddr>> + 'a>
+        where
+            TestsIdType: 'a,
+        {
+            Box::new(self.nodes.iter())
+        }
+    }
+
+    #[test]
+    fn test_report_failure_minimal_table() {
+        let node_table = MinimalNodeTable { nodes: vec![] };
+        let mut svc: Service<TestsIdType, net::SocketAddr, MinimalNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        let other = test::new_node_with_port(test::make_id(44), 8009);
+        svc.set_max_failures(2);
+
+        assert!(svc.handler.on_ping(&node));
+        assert!(svc.handler.on_ping(&other));
+        assert!(!svc.report_failure(&node));
+        assert_eq!(Some(&1), svc.failures.get(&node.id));
+        assert!(svc.report_failure(&node));
+        assert!(!svc.node_table().contains(&node.id));
+        assert!(svc.node_table().contains(&other.id));
+        assert_eq!(1, svc.node_table().len());
+        assert!(svc.failures.is_empty());
+    }
+
+    #[test]
+    fn test_report_failure_removes_node() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        svc.set_max_failures(2);
+
+        assert!(svc.handler.on_ping(&node));
+        assert!(!svc.report_failure(&node));
+        svc.report_success(&node);
+        assert!(!svc.report_failure(&node));
+        assert!(svc.node_table().node.is_some());
+        assert!(svc.report_failure(&node));
+        assert!(svc.node_table().node.is_none());
+        assert!(!svc.report_failure(&node));
+        assert!(svc.failures.is_empty());
+
+        // Failures are forgotten once the node leaves the table
+        assert!(svc.handler.on_ping(&node));
+        assert!(!svc.report_failure(&node));
+        svc.node_table_mut().node = None;
+        svc.clean_up(|_| true);
+        assert!(svc.failures.is_empty());
+    }
+
+    #[test]
+    fn test_node_validator() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        svc.set_node_validator(|node| node.id != test::make_id(43));
+
+        assert!(svc.handler.on_ping(&test::new_node(test::make_id(43))));
+        assert!(svc.node_table().node.is_none());
+        assert!(svc.handler.on_ping(&test::new_node(test::make_id(44))));
+        assert_eq!(
+            test::make_id(44),
+            svc.node_table().node.as_ref().unwrap().id
+        );
+    }
+
+    #[test]
+    fn test_bep42_validates_source() {
+        use super::super::bep42;
+
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        svc.set_node_validator(bep42::validate_node);
+        let this = test::new_node(test::make_id(42));
+        let source: net::SocketAddr = "21.75.31.124:8008".parse().unwrap();
+        // Private addresses are exempt, but only the source counts
+        let liar = Node {
+            id: bep42::generate_id(&"124.31.75.21".parse().unwrap()),
+            address: "10.0.0.1:8008".parse().unwrap(),
+        };
+        let request = svc.new_request(liar, RequestPayload::Ping);
+        svc.handler.handle_request(request, &source, this.clone());
+        assert!(svc.node_table().node.is_none());
+
+        let honest = Node {
+            id: bep42::generate_id(&source.ip()),
+            address: "10.0.0.1:8008".parse().unwrap(),
+        };
+        let request = svc.new_request(honest.clone(), RequestPayload::Ping);
+        svc.handler.handle_request(request, &source, this);
+        let stored = svc.node_table().node.clone().unwrap();
+        assert_eq!(honest.id, stored.id);
+        assert_eq!(source, stored.address);
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        let request = |payload| Request {
+            caller: node.clone(),
+            request_id: test::make_id(1),
+            payload,
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+        let responder = test::new_node(test::make_id(42));
+        let mut handle = |payload| {
+            svc.handler_mut()
+                .handle_request(request(payload), &node.address, responder.clone())
+        };
+
+        let find = RequestPayload::FindValue(test::make_id(44));
+        let response = handle(find).unwrap();
+        match response.payload {
+            ResponsePayload::NodesFound(..) => {}
+            _ => panic!("wrong result"),
+        }
+        let token = response.token.unwrap();
+
+        let store = RequestPayload::Store(test::make_id(44), "foobar".to_string(), token);
+        match handle(store).unwrap().payload {
+            ResponsePayload::NoResult => {}
+            _ => panic!("wrong result"),
+        }
+        let find = RequestPayload::FindValue(test::make_id(44));
+        match handle(find).unwrap().payload {
+            ResponsePayload::ValueFound(values) => assert_eq!(vec!["foobar"], values),
+            _ => panic!("wrong result"),
+        }
+        let find = RequestPayload::FindNode(test::make_id(43));
+        let response = handle(find).unwrap();
+        assert!(response.token.is_some());
+        match response.payload {
+            ResponsePayload::NodesFound(nodes) => assert_eq!(1, nodes.len()),
+            _ => panic!("wrong result"),
+        }
+        assert!(handle(RequestPayload::Ping).unwrap().token.is_none());
+
+        let store = RequestPayload::Store(test::make_id(44), "foobar".to_string(), vec![1]);
+        match handle(store).unwrap().payload {
+            ResponsePayload::Error(error) => assert_eq!(PROTOCOL_ERROR, error.code),
+            _ => panic!("wrong result"),
+        }
+    }
+
+    #[test]
+    fn test_store_requires_token() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        let other = test::new_node_with_port(test::make_id(44), 8009);
+        let id = test::make_id(45);
+
+        assert!(!svc
+            .handler
+            .on_store(&node, &id, "foobar".to_string(), &[1, 2, 3]));
+        let token = svc.handler.token_for(&other.address);
+        assert!(!svc
+            .handler
+            .on_store(&node, &id, "foobar".to_string(), &token));
+        assert!(svc.stored_data().is_empty());
+        assert!(svc.node_table().node.is_none());
+
+        let token = svc.handler.token_for(&node.address);
+        assert!(svc
+            .handler
+            .on_store(&node, &id, "foobar".to_string(), &token));
+        assert_eq!("foobar", svc.stored_data()[&id]);
+
+        // Tokens are bound to the address the request came from
+        let store = |token| Request {
+            caller: node.clone(),
+            request_id: test::make_id(1),
+            payload: RequestPayload::Store(id.clone(), "other".to_string(), token),
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+        let this = test::new_node(test::make_id(42));
+        let response = svc
+            .handler
+            .handle_request(store(token), &other.address, this.clone())
+            .unwrap();
+        match response.payload {
+            ResponsePayload::Error(error) => assert_eq!(PROTOCOL_ERROR, error.code),
+            _ => panic!("wrong payload"),
+        }
+        let token = svc.handler.token_for(&other.address);
+        let response = svc
+            .handler
+            .handle_request(store(token), &other.address, this)
+            .unwrap();
+        match response.payload {
+            ResponsePayload::NoResult => {}
+            _ => panic!("wrong payload"),
+        }
+        assert_eq!("other", svc.stored_data()[&id]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        svc.handler.on_ping(&node);
+
+        let res = svc.lookup(&node.id, |n, id| {
+            assert_eq!(test::make_id(43), n.id);
+            assert_eq!(test::make_id(43), *id);
+            // Our own node is never queried
+            FindResult::ClosestNodes(vec![test::new_node(test::make_id(42))])
+        });
+        match res {
+            FindResult::ClosestNodes(nodes) => assert_eq!(1, nodes.len()),
+            _ => panic!("wrong result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_lookup_own_id() {
+        let table = KNodeTable::new_with_details(test::make_id(42), 8, 8);
+        let mut svc: Service<TestsIdType, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(table, test::make_id(42));
+        for id in &[40, 43] {
+            svc.handler.on_ping(&test::new_node(test::make_id(*id)));
+        }
+
+        let own_id = test::make_id(42);
+        let res = svc.lookup(&own_id, |_, _| FindResult::ClosestNodes(Vec::new()));
+        match res {
+            FindResult::ClosestNodes(nodes) => {
+                assert_eq!(test::make_id(43), nodes[0].id);
+                assert_eq!(2, nodes.len());
+            }
+            _ => panic!("wrong result {:?}", res),
+        }
+        assert_eq!(2, svc.new_lookup(&own_id, 2).paths());
+
+        // Others looking us up are answered too
+        let caller = test::new_node(test::make_id(43));
+        let nodes = svc.handler.on_find_node(&caller, &own_id);
+        assert_eq!(2, nodes.len());
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let mut svc: Service<u64, net::SocketAddr, KNodeTable<u64, net::SocketAddr>, String> =
+            Service::new_with_id(KNodeTable::new_with_details(0, 2, 8), 0);
+        let node = |id: u64| Node {
+            id,
+            address: net::SocketAddr::from(([127, 0, 0, 1], 8000 + id as u16)),
+        };
+        assert!(svc.estimate_size().is_none());
+        svc.node_table_mut().update(&node(1));
+        svc.node_table_mut().update(&node(2));
+        assert!(svc.estimate_size().is_none());
+        // Bucket 2 is full, so buckets 0 and 1 have all nodes at their distance
+        svc.node_table_mut().update(&node(4));
+        svc.node_table_mut().update(&node(5));
+        assert_eq!(2, svc.estimate_size().unwrap().samples);
+
+        let far: Vec<_> = (0x81..0x85).map(node).collect();
+        svc.lookup(&0x80, |n, _| {
+            if n.id < 0x80 {
+                FindResult::ClosestNodes(far.clone())
+            } else {
+                FindResult::ClosestNodes(vec![])
+            }
+        });
+        let estimate = svc.estimate_size().unwrap();
+        assert!(estimate.samples > 2);
+        assert!(estimate.low <= estimate.estimate && estimate.estimate <= estimate.high);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        svc.set_rate_limits(RateLimits {
+            per_source: Some(RateLimit {
+                rate: 0.001,
+                burst: 2.0,
+            }),
+            global: None,
+        });
+        let node = test::new_node(test::make_id(43));
+        let responder = test::new_node(test::make_id(42));
+        let mut ping = |source: &net::SocketAddr| {
+            let request = Request {
+                caller: node.clone(),
+                request_id: test::make_id(1),
+                payload: RequestPayload::Ping,
+                version: Version::current(),
+                read_only: false,
+                want: None,
+                signature: None,
+            };
+            svc.handler
+                .handle_request(request, source, responder.clone())
+                .is_some()
+        };
+
+        assert!(ping(&node.address));
+        assert!(ping(&node.address));
+        assert!(!ping(&node.address));
+        // Other ports of the same host share the limit
+        assert!(!ping(&"127.0.0.1:9009".parse().unwrap()));
+        assert!(ping(&"127.0.0.2:8008".parse().unwrap()));
+        assert_eq!(2, svc.rate_limited_count());
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        let banned = test::new_node_with_port(test::make_id(44), 8009);
+        svc.ban_list_mut().ban_address(banned.address, None);
+
+        let request = |caller: &Node<TestsIdType, net::SocketAddr>| Request {
+            caller: caller.clone(),
+            request_id: test::make_id(1),
+            payload: RequestPayload::Ping,
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+        assert!(svc
+            .handler
+            .handle_request(request(&banned), &banned.address, node.clone())
+            .is_none());
+        // Claiming another address does not lift the ban
+        let disguised = Node {
+            id: banned.id.clone(),
+            address: node.address,
+        };
+        assert!(svc
+            .handler
+            .handle_request(request(&disguised), &banned.address, node.clone())
+            .is_none());
+        let response = Response {
+            request: request(&node),
+            responder: disguised,
+            payload: ResponsePayload::NoResult,
+            version: Version::current(),
+            token: None,
+            observed_address: None,
+            signature: None,
+        };
+        assert!(!svc.handler.handle_response(&response, &banned.address));
+        svc.handler.on_ping(&banned);
+        assert!(svc.node_table().node.is_none());
+
+        let res = svc.lookup(&node.id, |_, _| panic!("nothing to query"));
+        match res {
+            FindResult::Nothing => {}
+            _ => panic!("wrong result {:?}", res),
+        }
+
+        svc.handler.on_ping(&node);
+        let res = svc.lookup(&node.id, |_, _| {
+            FindResult::ClosestNodes(vec![node.clone(), banned.clone()])
+        });
+        match res {
+            FindResult::ClosestNodes(nodes) => {
+                assert_eq!(1, nodes.len());
+                assert_eq!(node.id, nodes[0].id);
+            }
+            _ => panic!("wrong result {:?}", res),
+        }
+
+        svc.ban_list_mut().ban_id(node.id.clone(), None);
+        svc.clean_up(|_| panic!("no nodes to check"));
+        assert!(svc.node_table().node.is_none());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let node = test::new_node(test::make_id(43));
+        let this = test::new_node(test::make_id(42));
+
+        let mut request = svc.new_request(this.clone(), RequestPayload::Ping);
+        assert!(!request.read_only);
+        svc.set_read_only(true);
+        assert!(svc.read_only());
+        request = svc.new_request(this.clone(), RequestPayload::Ping);
+        assert!(request.read_only);
+
+        // Read-only service does not answer
+        request.caller = node.clone();
+        assert!(svc
+            .handler
+            .handle_request(request, &node.address, this.clone())
+            .is_none());
+        assert!(svc.node_table().node.is_none());
+
+        // Read-only callers get answers, but are not remembered
+        svc.set_read_only(false);
+        let mut request = svc.new_request(node.clone(), RequestPayload::FindNode(node.id.clone()));
+        request.read_only = true;
+        let response = svc
+            .handler
+            .handle_request(request, &node.address, this.clone())
+            .unwrap();
+        match response.payload {
+            ResponsePayload::NodesFound(ref nodes) => assert!(nodes.is_empty()),
+            _ => panic!("wrong payload"),
+        }
+        assert!(response.token.is_some());
+        assert!(svc.node_table().node.is_none());
+
+        let request = svc.new_request(node.clone(), RequestPayload::Ping);
+        assert!(svc
+            .handler
+            .handle_request(request, &node.address, this)
+            .is_some());
+        assert_eq!(node.id, svc.node_table().node.as_ref().unwrap().id);
+    }
+
+    #[test]
+    fn test_external_address() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let this = test::new_node(test::make_id(42));
+        let external: net::SocketAddr = "10.0.0.1:8008".parse().unwrap();
+        let changes = Arc::new(AtomicUsize::new(0));
+        let counter = changes.clone();
+        svc.set_min_address_votes(2);
+        assert_eq!(2, svc.min_address_votes());
+        svc.set_address_listener(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let response = |svc: &Service<_, _, _, _>, responder| Response {
+            request: svc.new_request(this.clone(), RequestPayload::Ping),
+            responder: test::new_node(test::make_id(responder)),
+            payload: ResponsePayload::NoResult,
+            version: Version::current(),
+            token: None,
+            observed_address: Some(external),
+            signature: None,
+        };
+        let first_host: net::SocketAddr = "10.0.0.3:8008".parse().unwrap();
+        let second_host: net::SocketAddr = "10.0.0.4:8008".parse().unwrap();
+        let first = response(&svc, 1);
+        assert!(svc.handler.handle_response(&first, &first_host));
+        assert!(svc.handler.handle_response(&first, &first_host));
+        assert!(svc.external_address().is_none());
+        // Other IDs and ports of the same host do not count
+        let sybil = response(&svc, 3);
+        let sybil_port: net::SocketAddr = "10.0.0.3:9009".parse().unwrap();
+        assert!(svc.handler.handle_response(&sybil, &sybil_port));
+        assert!(svc.external_address().is_none());
+        let second = response(&svc, 2);
+        assert!(svc.handler.handle_response(&second, &second_host));
+        assert_eq!(Some(&external), svc.external_address());
+        assert_eq!(1, changes.load(Ordering::SeqCst));
+
+        // Replies echo the address the request came from, not the one claimed
+        let caller = test::new_node(test::make_id(43));
+        let source: net::SocketAddr = "10.0.0.2:9009".parse().unwrap();
+        let request = svc.new_request(caller.clone(), RequestPayload::Ping);
+        let reply = svc
+            .handler
+            .handle_request(request, &source, this.clone())
+            .unwrap();
+        assert_eq!(Some(source), reply.observed_address);
+        assert_ne!(Some(caller.address), reply.observed_address);
+    }
+
+    #[test]
+    fn test_sender_stored_with_source() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let this = test::new_node(test::make_id(42));
+        // Claims the address of someone else
+        let caller = test::new_node(test::make_id(43));
+        let source: net::SocketAddr = "10.0.0.2:9009".parse().unwrap();
+        let request = svc.new_request(caller.clone(), RequestPayload::Ping);
+        svc.handler.handle_request(request, &source, this.clone());
+        let stored = svc.node_table().node.clone().unwrap();
+        assert_eq!(caller.id, stored.id);
+        assert_eq!(source, stored.address);
+
+        svc.node_table_mut().node = None;
+        let response = Response {
+            request: svc.new_request(this.clone(), RequestPayload::Ping),
+            responder: caller.clone(),
+            payload: ResponsePayload::NoResult,
+            version: Version::current(),
+            token: None,
+            observed_address: None,
+            signature: None,
+        };
+        assert!(svc.handler.handle_response(&response, &source));
+        assert_eq!(source, svc.node_table().node.clone().unwrap().address);
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        use super::super::protocol::Want;
+        use super::super::{DualStackTable, KNodeTable};
+
+        let table = DualStackTable::new(
+            KNodeTable::new_with_details(test::make_id(42), 8, 8),
+            KNodeTable::new_with_details(test::make_id(42), 8, 8),
+        );
+        let mut svc: Service<TestsIdType, net::SocketAddr, _, String> =
+            Service::new_with_id(table, test::make_id(42));
+        let this = test::new_node(test::make_id(42));
+        let v4 = test::new_node(test::make_id(1));
+        let v6 = Node {
+            id: test::make_id(2),
+            address: "[::1]:8008".parse().unwrap(),
+        };
+        svc.handler.on_ping(&v4);
+        svc.handler.on_ping(&v6);
+
+        let mut find = |caller: &Node<_, _>, source: &net::SocketAddr, want| {
+            let mut request =
+                svc.new_request(caller.clone(), RequestPayload::FindNode(test::make_id(3)));
+            request.want = want;
+            let response = svc
+                .handler
+                .handle_request(request, source, this.clone())
+                .unwrap();
+            match response.payload {
+                ResponsePayload::NodesFound(nodes) => {
+                    let mut ids: Vec<_> = nodes.into_iter().map(|n| n.id[0]).collect();
+                    ids.sort();
+                    ids
+                }
+                _ => panic!("wrong payload"),
+            }
+        };
+        assert_eq!(vec![1], find(&v4, &v4.address, None));
+        assert_eq!(vec![2], find(&v6, &v6.address, None));
+        assert_eq!(vec![1, 2], find(&v4, &v4.address, Some(Want::both())));
+        let v6_only = Want {
+            ipv4: false,
+            ipv6: true,
+        };
+        assert_eq!(vec![2], find(&v4, &v4.address, Some(v6_only)));
+        // The family is that of the source, not of the claimed address
+        let other = test::new_node(test::make_id(5));
+        assert_eq!(vec![2], find(&other, &v6.address, None));
+    }
+
+    #[test]
+    fn test_reply_families_single_table() {
+        use super::super::protocol::Want;
+
+        let table = KNodeTable::new_with_details(test::make_id(42), 8, 8);
+        let mut svc: Service<TestsIdType, net::SocketAddr, _, String> =
+            Service::new_with_id(table, test::make_id(42));
+        let this = test::new_node(test::make_id(42));
+        let v4 = test::new_node(test::make_id(1));
+        let v6 = Node {
+            id: test::make_id(2),
+            address: "[::1]:8008".parse().unwrap(),
+        };
+        svc.handler.on_ping(&v4);
+        svc.handler.on_ping(&v6);
+
+        let mut find = |id, source: &net::SocketAddr, want| {
+            let mut request = svc.new_request(v4.clone(), RequestPayload::FindNode(id));
+            request.want = want;
+            let response = svc
+                .handler
+                .handle_request(request, source, this.clone())
+                .unwrap();
+            match response.payload {
+                ResponsePayload::NodesFound(nodes) => nodes.len(),
+                _ => panic!("wrong payload"),
+            }
+        };
+        assert_eq!(1, find(test::make_id(3), &v4.address, None));
+        assert_eq!(2, find(test::make_id(3), &v4.address, Some(Want::both())));
+        // Including when looking for this node
+        assert_eq!(1, find(test::make_id(42), &v6.address, None));
+    }
+
+    #[test]
+    fn test_versions() {
+        use super::super::KNodeTable;
+
+        let table = KNodeTable::new_with_details(test::make_id(42), 8, 8);
+        let mut svc: Service<TestsIdType, net::SocketAddr, _, String> =
+            Service::new_with_id(table, test::make_id(42));
+        let this = test::new_node(test::make_id(42));
+        let node = test::new_node(test::make_id(1));
+        let upgraded = Version {
+            number: 2,
+            features: vec!["foo".to_string()],
+        };
+        svc.set_version(Version {
+            number: 1,
+            features: vec!["bar".to_string()],
+        });
+
+        let mut request = svc.new_request(node.clone(), RequestPayload::Ping);
+        assert_eq!(svc.version(), &request.version);
+        request.version = upgraded.clone();
+        let response = svc
+            .handler
+            .handle_request(request, &node.address, this.clone())
+            .unwrap();
+        assert!(response.version.supports("bar"));
+        assert_eq!(Some(&upgraded), svc.node_table().version(&node.id));
+
+        // Features are capped in number and length
+        let mut request = svc.new_request(node.clone(), RequestPayload::Ping);
+        request.version = upgraded.clone();
+        request.version.features = (0..100).map(|i| format!("feature{}", i)).collect();
+        request.version.features.insert(0, "x".repeat(1000));
+        svc.handler
+            .handle_request(request, &node.address, this.clone())
+            .unwrap();
+        let stored = svc.node_table().version(&node.id).unwrap().clone();
+        assert_eq!(MAX_FEATURES, stored.features.len());
+        assert_eq!("feature0", stored.features[0]);
+
+        // Unknown methods are answered with an error, caller is not recorded
+        let other = test::new_node(test::make_id(2));
+        let request = svc.new_request(other.clone(), RequestPayload::Unknown("foo".to_string()));
+        let response = svc
+            .handler
+            .handle_request(request, &other.address, this.clone())
+            .unwrap();
+        match response.payload {
+            ResponsePayload::Error(error) => assert_eq!(METHOD_UNKNOWN, error.code),
+            _ => panic!("wrong payload"),
+        }
+        assert!(svc.node_table().version(&other.id).is_none());
+        assert!(!svc.node_table().contains(&other.id));
+
+        let response = Response {
+            request: svc.new_request(this.clone(), RequestPayload::Ping),
+            responder: other.clone(),
+            payload: ResponsePayload::NoResult,
+            version: Version::default(),
+            token: None,
+            observed_address: None,
+            signature: None,
+        };
+        assert!(svc.handler.handle_response(&response, &other.address));
+        assert_eq!(0, svc.node_table().version(&other.id).unwrap().number);
+
+        let table = svc.node_table();
+        let upgraded_only = table.find_filtered(&test::make_id(3), 8, &|n| {
+            table.version(&n.id).is_some_and(|v| v.number >= 2)
+        });
+        assert_eq!(
+            vec![node.id],
+            upgraded_only.into_iter().map(|n| n.id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_multi_value() {
+        use super::super::multivalue::MultiValueStore;
+        use std::time::Duration;
+
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        assert!(svc.multi_values().is_none());
+        svc.enable_multi_value(MultiValueStore::new_with_details(
+            8,
+            2,
+            Duration::from_secs(60),
+        ));
+        let id = test::make_id(50);
+
+        for publisher in 1..4 {
+            let node = test::new_node_with_port(test::make_id(publisher), 8000 + publisher as u16);
+            let token = svc.handler.token_for(&node.address);
+            let value = format!("value{}", publisher);
+            assert!(svc.handler.on_store(&node, &id, value, &token));
+        }
+        assert!(svc.stored_data().is_empty());
+        assert_eq!(3, svc.multi_values().unwrap().get(&id).len());
+
+        // Claiming the ID of another publisher does not replace its value
+        let this = test::new_node(test::make_id(42));
+        let impostor = test::new_node_with_port(test::make_id(1), 9000);
+        let token = svc.handler.token_for(&impostor.address);
+        let request = Request {
+            caller: test::new_node_with_port(test::make_id(1), 8001),
+            request_id: test::make_id(1),
+            payload: RequestPayload::Store(id.clone(), "forged".to_string(), token),
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+        svc.handler
+            .handle_request(request, &impostor.address, this)
+            .unwrap();
+        let values = svc.multi_values().unwrap().get(&id);
+        assert_eq!(4, values.len());
+        assert!(values.iter().any(|v| v.value == "value1"));
+
+        let node = test::new_node(test::make_id(43));
+        match svc.handler.on_find_value(&node, &id) {
+            FindResult::Value(values) => {
+                assert_eq!(2, values.len());
+                assert!(values[0] != values[1]);
+            }
+            res => panic!("wrong result {:?}", res),
+        }
+        // Callers before version 2 get one value
+        let mut request = svc.new_request(node.clone(), RequestPayload::FindValue(id.clone()));
+        request.version = Version {
+            number: 1,
+            features: Vec::new(),
+        };
+        let this = test::new_node(test::make_id(42));
+        match svc
+            .handler
+            .handle_request(request, &node.address, this)
+            .unwrap()
+            .payload
+        {
+            ResponsePayload::ValueFound(values) => assert_eq!(1, values.len()),
+            _ => panic!("wrong payload"),
+        }
+
+        svc.multi_values_mut()
+            .unwrap()
+            .remove(&id, &impostor.address);
+        assert_eq!(3, svc.multi_values().unwrap().len());
+    }
+
+    #[test]
+    fn test_providers() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        let this = test::new_node(test::make_id(42));
+        let provider = test::new_node_with_port(test::make_id(43), 8009);
+        // Claims another address, providers are stored with the real one
+        let caller = test::new_node(test::make_id(43));
+        let id = test::make_id(50);
+        let request = |payload| Request {
+            caller: caller.clone(),
+            request_id: test::make_id(1),
+            payload,
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+
+        let response = svc
+            .handler
+            .handle_request(
+                request(RequestPayload::GetProviders(id.clone())),
+                &provider.address,
+                this.clone(),
+            )
+            .unwrap();
+        match response.payload {
+            ResponsePayload::ProvidersFound(ref providers, _) => assert!(providers.is_empty()),
+            _ => panic!("wrong payload"),
+        }
+        let token = response.token.unwrap();
+
+        let add = RequestPayload::AddProvider(id.clone(), vec![1]);
+        match svc
+            .handler
+            .handle_request(request(add), &provider.address, this.clone())
+            .unwrap()
+            .payload
+        {
+            ResponsePayload::Error(error) => assert_eq!(PROTOCOL_ERROR, error.code),
+            _ => panic!("wrong payload"),
+        }
+        let add = RequestPayload::AddProvider(id.clone(), token);
+        match svc
+            .handler
+            .handle_request(request(add), &provider.address, this.clone())
+            .unwrap()
+            .payload
+        {
+            ResponsePayload::NoResult => {}
+            _ => panic!("wrong payload"),
+        }
+        assert_eq!(1, svc.providers().len());
+
+        let other = test::new_node(test::make_id(44));
+        let (providers, _) = svc.handler.on_get_providers(&other, &id);
+        assert_eq!(1, providers.len());
+        assert_eq!(provider.address, providers[0].address);
+
+        // Claiming the ID of a provider does not replace its record
+        let impostor = test::new_node_with_port(test::make_id(43), 9000);
+        let token = svc.handler.token_for(&impostor.address);
+        assert!(svc.handler.on_add_provider(&impostor, &id, &token));
+        let stored = svc.providers().get(&id);
+        assert_eq!(2, stored.len());
+        assert!(stored.iter().any(|v| v.value.address == provider.address));
+
+        svc.ban_list_mut().ban_id(provider.id.clone(), None);
+        assert!(svc.handler.on_get_providers(&other, &id).0.is_empty());
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        svc.set_key_sample_size(2);
+        svc.set_key_sample_interval(Duration::from_secs(60));
+        for i in 0..5 {
+            svc.stored_data_mut()
+                .insert(test::make_id(50 + i), "value".to_string());
+        }
+        let this = test::new_node(test::make_id(42));
+        let caller = test::new_node_with_port(test::make_id(43), 8009);
+        let request = Request {
+            caller: caller.clone(),
+            request_id: test::make_id(1),
+            payload: RequestPayload::SampleKeys(test::make_id(50)),
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+        let response = svc
+            .handler
+            .handle_request(request, &caller.address, this)
+            .unwrap();
+        assert!(response.token.is_none());
+        match response.payload {
+            ResponsePayload::KeysSampled(sample, _) => {
+                assert_eq!(2, sample.keys.len());
+                assert_ne!(sample.keys[0], sample.keys[1]);
+                assert!(sample
+                    .keys
+                    .iter()
+                    .all(|k| svc.stored_data().contains_key(k)));
+                assert_eq!(5, sample.total);
+                assert_eq!(Duration::from_secs(60), sample.interval);
+            }
+            _ => panic!("wrong payload"),
+        }
+
+        // Smaller stores are returned as a whole
+        svc.set_key_sample_size(20);
+        let other = test::new_node(test::make_id(44));
+        let (sample, _) = svc.handler.on_sample_keys(&other, &test::make_id(50));
+        assert_eq!(5, sample.keys.len());
+    }
+
+    #[test]
+    fn test_events() {
+        use super::super::multivalue::MultiValueStore;
+
+        let node_table = DummyNodeTable { node: None };
+        let mut svc: Service<TestsIdType, net::SocketAddr, DummyNodeTable, String> =
+            Service::new(node_table);
+        svc.enable_multi_value(MultiValueStore::new_with_details(
+            4,
+            4,
+            Duration::from_secs(0),
+        ));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        svc.set_event_listener(move |event| events_clone.lock().unwrap().push(event.clone()));
+
+        let this = test::new_node(test::make_id(42));
+        let sender = test::new_node_with_port(test::make_id(43), 8009);
+        let other = test::new_node_with_port(test::make_id(44), 8010);
+        let id = test::make_id(50);
+        let request = |caller: &Node<TestsIdType, net::SocketAddr>, payload| Request {
+            caller: caller.clone(),
+            request_id: test::make_id(1),
+            payload,
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+
+        svc.handler.handle_request(
+            request(&sender, RequestPayload::Ping),
+            &sender.address,
+            this.clone(),
+        );
+        // Dummy table has space for one node only
+        svc.handler.handle_request(
+            request(&other, RequestPayload::Ping),
+            &other.address,
+            this.clone(),
+        );
+        let token = svc
+            .handler
+            .handle_request(
+                request(&sender, RequestPayload::FindNode(id.clone())),
+                &sender.address,
+                this.clone(),
+            )
+            .unwrap()
+            .token
+            .unwrap();
+        let store = RequestPayload::Store(id.clone(), "value".to_string(), token);
+        svc.handler
+            .handle_request(request(&sender, store), &sender.address, this);
+        svc.set_providers(MultiValueStore::new_with_details(
+            4,
+            4,
+            Duration::from_secs(0),
+        ));
+        svc.providers_mut()
+            .insert(id.clone(), sender.address, sender.clone());
+        // Values and providers expire immediately, the node fails the check
+        svc.clean_up(|_| false);
+        svc.lookup(&id, |_, _| FindResult::Nothing);
+
+        let events = events.lock().unwrap();
+        let names: Vec<_> = events
+            .iter()
+            .map(|event| match *event {
+                Event::NodeAdded(..) => "added",
+                Event::NodeEvicted(..) => "evicted",
+                Event::BucketFull(..) => "full",
+                Event::ValueStored(..) => "stored",
+                Event::ValueExpired(..) => "expired",
+                Event::ProvidersExpired(..) => "providers expired",
+                Event::RequestReceived(..) => "request",
+                Event::LookupCompleted { .. } => "lookup",
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "request",
+                "added",
+                "request",
+                "full",
+                "request",
+                "full",
+                "request",
+                "stored",
+                "full",
+                "expired",
+                "providers expired",
+                "evicted",
+                "lookup",
+            ],
+            names
+        );
+        match events[3] {
+            Event::BucketFull(ref node) => assert_eq!(other.id, node.id),
+            _ => unreachable!(),
+        }
+        match events[7] {
+            Event::ValueStored(ref stored, ref node) => {
+                assert_eq!(id, *stored);
+                assert_eq!(sender.id, node.id);
+            }
+            _ => unreachable!(),
+        }
+        match events[10] {
+            Event::ProvidersExpired(ref expired) => assert_eq!(id, *expired),
+            _ => unreachable!(),
+        }
+        match events[11] {
+            Event::NodeEvicted(ref node) => assert_eq!(sender.id, node.id),
+            _ => unreachable!(),
+        }
+        match events[12] {
+            Event::LookupCompleted {
+                ref target,
+                found_value,
+                nodes,
+            } => {
+                assert_eq!(id, *target);
+                assert!(!found_value);
+                assert_eq!(0, nodes);
+            }
+            _ => unreachable!(),
+        }
+    }
 }
@@ -0,0 +1,533 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! S/Kademlia cryptographic node IDs and signed messages.
+//!
+//! See [S/Kademlia paper](https://doi.org/10.1109/ICPADS.2007.4447808)
+//! for details. Node ID is a SHA-1 hash of the node's ed25519 public key,
+//! optionally with a static crypto puzzle: SHA-1 hash of the node ID must
+//! have at least `difficulty` leading zero bits. Every message is signed
+//! with the key, so nobody can claim somebody else's ID.
+//!
+//! Use `SKademlia` with `Service::set_authenticator` to reject unsigned
+//! messages, and `sign_request`/`sign_response` for outgoing ones.
+
+use std::net;
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+
+use super::protocol::{
+    Authenticator, Request, RequestPayload, Response, ResponsePayload, Signature, Version,
+};
+use super::{GenericId, Node};
+
+/// ed25519 key pair identifying a node.
+pub struct Keypair {
+    key: SigningKey,
+}
+
+impl Keypair {
+    /// Generate a new random key pair.
+    pub fn generate() -> Keypair {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill(&mut secret);
+        Keypair {
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Generate key pairs until one solves the crypto puzzle.
+    ///
+    /// Expect around 2^difficulty attempts.
+    pub fn generate_with_difficulty(difficulty: usize) -> Keypair {
+        loop {
+            let keypair = Keypair::generate();
+            if check_puzzle(&keypair.node_id(), difficulty) {
+                return keypair;
+            }
+        }
+    }
+
+    /// Public key of the node.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    /// Node ID derived from the public key.
+    pub fn node_id(&self) -> Vec<u8> {
+        node_id(&self.public_key())
+    }
+
+    /// Sign arbitrary data.
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        Signature {
+            public_key: self.public_key(),
+            signature: self.key.sign(data).to_bytes(),
+        }
+    }
+}
+
+/// Derive node ID from a public key.
+pub fn node_id(public_key: &[u8; 32]) -> Vec<u8> {
+    Sha1::digest(public_key).to_vec()
+}
+
+/// Check that node ID solves the crypto puzzle of given difficulty.
+pub fn check_puzzle(id: &[u8], difficulty: usize) -> bool {
+    let hash = Sha1::digest(id).to_vec();
+    hash.len() * 8 - hash.bits() >= difficulty
+}
+
+/// Verify that data is signed by the owner of node ID.
+pub fn verify(node_id: &[u8], data: &[u8], signature: &Signature, difficulty: usize) -> bool {
+    if self::node_id(&signature.public_key)[..] != *node_id || !check_puzzle(node_id, difficulty) {
+        return false;
+    }
+    match VerifyingKey::from_bytes(&signature.public_key) {
+        Ok(key) => {
+            let sig = ed25519_dalek::Signature::from_bytes(&signature.signature);
+            key.verify_strict(data, &sig).is_ok()
+        }
+        Err(..) => false,
+    }
+}
+
+/// Canonical binary representation, which is covered by signatures.
+pub trait Signable {
+    fn write_signed(&self, buf: &mut Vec<u8>);
+}
+
+impl Signable for u64 {
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Signable for [u8] {
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).write_signed(buf);
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Signable for Vec<u8> {
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        self[..].write_signed(buf);
+    }
+}
+
+impl Signable for String {
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().write_signed(buf);
+    }
+}
+
+impl Signable for net::SocketAddr {
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        match self.ip() {
+            net::IpAddr::V4(ip) => {
+                buf.push(4);
+                buf.extend_from_slice(&ip.octets());
+            }
+            net::IpAddr::V6(ip) => {
+                buf.push(6);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+    }
+}
+
+impl<TId, TAddr> Signable for Node<TId, TAddr>
+where
+    TId: Signable,
+    TAddr: Signable,
+{
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        self.id.write_signed(buf);
+        self.address.write_signed(buf);
+    }
+}
+
+impl<TId, TValue> Signable for RequestPayload<TId, TValue>
+where
+    TId: Signable,
+    TValue: Signable,
+{
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        match *self {
+            RequestPayload::Ping => buf.push(0),
+            RequestPayload::FindNode(ref id) => {
+                buf.push(1);
+                id.write_signed(buf);
+            }
+            RequestPayload::FindValue(ref id) => {
+                buf.push(2);
+                id.write_signed(buf);
+            }
+            RequestPayload::Store(ref id, ref value, ref token) => {
+                buf.push(3);
+                id.write_signed(buf);
+                value.write_signed(buf);
+                token.write_signed(buf);
+            }
+            RequestPayload::AddProvider(ref id, ref token) => {
+                buf.push(5);
+                id.write_signed(buf);
+                token.write_signed(buf);
+            }
+            RequestPayload::GetProviders(ref id) => {
+                buf.push(6);
+                id.write_signed(buf);
+            }
+            RequestPayload::SampleKeys(ref id) => {
+                buf.push(7);
+                id.write_signed(buf);
+            }
+            RequestPayload::Unknown(ref method) => {
+                buf.push(4);
+                method.write_signed(buf);
+            }
+        }
+    }
+}
+
+impl Signable for Version {
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        u64::from(self.number).write_signed(buf);
+        (self.features.len() as u64).write_signed(buf);
+        for feature in &self.features {
+            feature.write_signed(buf);
+        }
+    }
+}
+
+impl<TId, TAddr, TValue> Signable for ResponsePayload<TId, TAddr, TValue>
+where
+    TId: Signable,
+    TAddr: Signable,
+    TValue: Signable,
+{
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        match *self {
+            ResponsePayload::NodesFound(ref nodes) => {
+                buf.push(0);
+                (nodes.len() as u64).write_signed(buf);
+                for node in nodes {
+                    node.write_signed(buf);
+                }
+            }
+            ResponsePayload::ValueFound(ref values) => {
+                buf.push(1);
+                (values.len() as u64).write_signed(buf);
+                for value in values {
+                    value.write_signed(buf);
+                }
+            }
+            ResponsePayload::ProvidersFound(ref providers, ref nodes) => {
+                buf.push(4);
+                for nodes in &[providers, nodes] {
+                    (nodes.len() as u64).write_signed(buf);
+                    for node in nodes.iter() {
+                        node.write_signed(buf);
+                    }
+                }
+            }
+            ResponsePayload::KeysSampled(ref sample, ref nodes) => {
+                buf.push(5);
+                (sample.keys.len() as u64).write_signed(buf);
+                for key in &sample.keys {
+                    key.write_signed(buf);
+                }
+                (sample.total as u64).write_signed(buf);
+                sample.interval.as_secs().write_signed(buf);
+                (nodes.len() as u64).write_signed(buf);
+                for node in nodes {
+                    node.write_signed(buf);
+                }
+            }
+            ResponsePayload::NoResult => buf.push(2),
+            ResponsePayload::Error(ref error) => {
+                buf.push(3);
+                u64::from(error.code).write_signed(buf);
+                error.message.write_signed(buf);
+            }
+        }
+    }
+}
+
+/// Bytes of the request covered by its signature.
+pub fn request_bytes<TId, TAddr, TValue>(request: &Request<TId, TAddr, TValue>) -> Vec<u8>
+where
+    TId: Signable,
+    TAddr: Signable,
+    TValue: Signable,
+{
+    let mut buf = b"request".to_vec();
+    request.caller.write_signed(&mut buf);
+    request.request_id.write_signed(&mut buf);
+    request.payload.write_signed(&mut buf);
+    request.version.write_signed(&mut buf);
+    buf.push(request.read_only as u8);
+    match request.want {
+        Some(want) => buf.push(1 | (want.ipv4 as u8) << 1 | (want.ipv6 as u8) << 2),
+        None => buf.push(0),
+    }
+    buf
+}
+
+/// Bytes of the response covered by its signature.
+pub fn response_bytes<TId, TAddr, TValue>(response: &Response<TId, TAddr, TValue>) -> Vec<u8>
+where
+    TId: Signable,
+    TAddr: Signable,
+    TValue: Signable,
+{
+    let mut buf = b"response".to_vec();
+    response.responder.write_signed(&mut buf);
+    response.request.request_id.write_signed(&mut buf);
+    response.payload.write_signed(&mut buf);
+    response.version.write_signed(&mut buf);
+    match response.token {
+        Some(ref token) => {
+            buf.push(1);
+            token.write_signed(&mut buf);
+        }
+        None => buf.push(0),
+    }
+    match response.observed_address {
+        Some(ref address) => {
+            buf.push(1);
+            address.write_signed(&mut buf);
+        }
+        None => buf.push(0),
+    }
+    buf
+}
+
+/// Sign an outgoing request.
+pub fn sign_request<TAddr, TValue>(keypair: &Keypair, request: &mut Request<Vec<u8>, TAddr, TValue>)
+where
+    TAddr: Signable,
+    TValue: Signable,
+{
+    request.signature = Some(keypair.sign(&request_bytes(request)));
+}
+
+/// Sign an outgoing response.
+pub fn sign_response<TAddr, TValue>(
+    keypair: &Keypair,
+    response: &mut Response<Vec<u8>, TAddr, TValue>,
+) where
+    TAddr: Signable,
+    TValue: Signable,
+{
+    response.signature = Some(keypair.sign(&response_bytes(response)));
+}
+
+/// Authenticator accepting only messages signed by the owner of the ID.
+pub struct SKademlia {
+    difficulty: usize,
+}
+
+impl SKademlia {
+    /// Create an authenticator with given crypto puzzle difficulty.
+    pub fn new(difficulty: usize) -> SKademlia {
+        SKademlia { difficulty }
+    }
+}
+
+impl<TAddr, TValue> Authenticator<Vec<u8>, TAddr, TValue> for SKademlia
+where
+    TAddr: Signable,
+    TValue: Signable,
+{
+    fn verify_request(&self, request: &Request<Vec<u8>, TAddr, TValue>) -> bool {
+        match request.signature {
+            Some(ref signature) => verify(
+                &request.caller.id,
+                &request_bytes(request),
+                signature,
+                self.difficulty,
+            ),
+            None => false,
+        }
+    }
+
+    fn verify_response(&self, response: &Response<Vec<u8>, TAddr, TValue>) -> bool {
+        match response.signature {
+            Some(ref signature) => verify(
+                &response.responder.id,
+                &response_bytes(response),
+                signature,
+                self.difficulty,
+            ),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+
+    use super::super::protocol::{
+        Authenticator, Request, RequestPayload, Response, ResponsePayload, Version, PROTOCOL_ERROR,
+    };
+    use super::super::service::Service;
+    use super::super::{GenericNodeTable, KNodeTable, Node};
+    use super::{check_puzzle, sign_request, sign_response, Keypair, SKademlia};
+
+    type TestRequest = Request<Vec<u8>, net::SocketAddr, String>;
+
+    fn new_node(keypair: &Keypair) -> Node<Vec<u8>, net::SocketAddr> {
+        Node {
+            id: keypair.node_id(),
+            address: "127.0.0.1:8008".parse().unwrap(),
+        }
+    }
+
+    fn signed_request(keypair: &Keypair) -> TestRequest {
+        let mut request = Request {
+            caller: new_node(keypair),
+            request_id: vec![1, 2, 3],
+            payload: RequestPayload::FindValue(vec![42]),
+            version: Version::current(),
+            read_only: false,
+            want: None,
+            signature: None,
+        };
+        sign_request(keypair, &mut request);
+        request
+    }
+
+    #[test]
+    fn test_node_id() {
+        let keypair = Keypair::generate();
+        assert_eq!(20, keypair.node_id().len());
+        assert!(keypair.node_id() != Keypair::generate().node_id());
+    }
+
+    #[test]
+    fn test_puzzle() {
+        let keypair = Keypair::generate_with_difficulty(6);
+        assert!(check_puzzle(&keypair.node_id(), 6));
+        assert!(check_puzzle(&keypair.node_id(), 0));
+    }
+
+    #[test]
+    fn test_verify_request() {
+        let auth = SKademlia::new(0);
+        let keypair = Keypair::generate();
+        let request = signed_request(&keypair);
+        assert!(auth.verify_request(&request));
+
+        let mut tampered = signed_request(&keypair);
+        tampered.payload = RequestPayload::FindValue(vec![43]);
+        assert!(!auth.verify_request(&tampered));
+
+        let mut tampered = signed_request(&keypair);
+        tampered.read_only = true;
+        assert!(!auth.verify_request(&tampered));
+
+        let mut unsigned = signed_request(&keypair);
+        unsigned.signature = None;
+        assert!(!auth.verify_request(&unsigned));
+
+        // Signature is valid, but the ID belongs to another key
+        let mut stolen_id = signed_request(&keypair);
+        stolen_id.caller.id = Keypair::generate().node_id();
+        sign_request(&keypair, &mut stolen_id);
+        assert!(!auth.verify_request(&stolen_id));
+    }
+
+    #[test]
+    fn test_verify_request_difficulty() {
+        let keypair = Keypair::generate_with_difficulty(4);
+        let request = signed_request(&keypair);
+        assert!(SKademlia::new(4).verify_request(&request));
+
+        let mut keypair = Keypair::generate();
+        while check_puzzle(&keypair.node_id(), 4) {
+            keypair = Keypair::generate();
+        }
+        let request = signed_request(&keypair);
+        assert!(!SKademlia::new(4).verify_request(&request));
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let auth = SKademlia::new(0);
+        let caller = Keypair::generate();
+        let responder = Keypair::generate();
+        let mut response = Response {
+            request: signed_request(&caller),
+            responder: new_node(&responder),
+            payload: ResponsePayload::NodesFound(vec![new_node(&caller)]),
+            version: Version::current(),
+            token: Some(vec![1, 2, 3]),
+            observed_address: Some(new_node(&caller).address),
+            signature: None,
+        };
+        assert!(!auth.verify_response(&response));
+        sign_response(&responder, &mut response);
+        assert!(auth.verify_response(&response));
+        response.token = Some(vec![1, 2, 4]);
+        assert!(!auth.verify_response(&response));
+        response.token = Some(vec![1, 2, 3]);
+        response.observed_address = Some("127.0.0.1:8009".parse().unwrap());
+        assert!(!auth.verify_response(&response));
+        response.observed_address = Some(new_node(&caller).address);
+        response.payload = ResponsePayload::NoResult;
+        assert!(!auth.verify_response(&response));
+    }
+
+    #[test]
+    fn test_service_rejects_unsigned() {
+        let own = Keypair::generate();
+        let table = KNodeTable::new_with_details(own.node_id(), 8, 160);
+        let mut svc: Service<Vec<u8>, net::SocketAddr, KNodeTable<_, _>, String> =
+            Service::new_with_id(table, own.node_id());
+        svc.set_authenticator(SKademlia::new(0));
+
+        let keypair = Keypair::generate();
+        let address = new_node(&keypair).address;
+        let token = svc.handler_mut().token_for(&address);
+        let mut request = signed_request(&keypair);
+        request.payload = RequestPayload::Store(vec![42], "value".to_string(), token.clone());
+        request.signature = None;
+        let responder = new_node(&own);
+        let response = svc
+            .handler_mut()
+            .handle_request(request, &address, responder.clone())
+            .unwrap();
+        match response.payload {
+            ResponsePayload::Error(error) => assert_eq!(PROTOCOL_ERROR, error.code),
+            _ => panic!("wrong payload"),
+        }
+        assert!(svc.node_table().is_empty());
+        assert!(svc.stored_data().is_empty());
+
+        let mut request = signed_request(&keypair);
+        request.payload = RequestPayload::Store(vec![42], "value".to_string(), token);
+        sign_request(&keypair, &mut request);
+        let response = svc
+            .handler_mut()
+            .handle_request(request, &address, responder)
+            .unwrap();
+        match response.payload {
+            ResponsePayload::NoResult => {}
+            _ => panic!("wrong payload"),
+        }
+        assert!(svc.node_table().contains(&keypair.node_id()));
+        assert_eq!("value", svc.stored_data()[&vec![42]]);
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2016 Dmitry "Divius" Tantsur <divius.inside@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Write tokens, proving that a node recently contacted us from its address.
+//!
+//! A token is a SHA-1 hash of a secret and the requester's address. The
+//! secret rotates every 5 minutes and the previous one is still accepted,
+//! so a token stays valid for 5 to 10 minutes.
+
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+
+use super::protocol::Token;
+
+static ROTATION_SECS: u64 = 300;
+
+/// Rotating secrets for generating and checking tokens.
+pub struct TokenSecrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+struct Sha1Hasher(Sha1);
+
+impl Hasher for Sha1Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+    fn finish(&self) -> u64 {
+        unreachable!("only used to feed bytes into SHA-1")
+    }
+}
+
+impl TokenSecrets {
+    pub fn new() -> TokenSecrets {
+        TokenSecrets {
+            current: random_secret(),
+            previous: random_secret(),
+            rotated_at: Instant::now(),
+        }
+    }
+
+    /// Generate a token for the address.
+    pub fn generate<TAddr: Hash>(&mut self, address: &TAddr) -> Token {
+        self.rotate_if_needed(Instant::now());
+        make_token(&self.current, address)
+    }
+
+    /// Check that the token was recently generated for the address.
+    pub fn verify<TAddr: Hash>(&mut self, address: &TAddr, token: &[u8]) -> bool {
+        self.rotate_if_needed(Instant::now());
+        make_token(&self.current, address)[..] == *token
+            || make_token(&self.previous, address)[..] == *token
+    }
+
+    fn rotate_if_needed(&mut self, now: Instant) {
+        let interval = Duration::from_secs(ROTATION_SECS);
+        let elapsed = now.duration_since(self.rotated_at);
+        if elapsed >= interval * 2 {
+            self.rotate();
+            self.rotate();
+        } else if elapsed >= interval {
+            self.rotate();
+        } else {
+            return;
+        }
+        self.rotated_at = now;
+    }
+
+    fn rotate(&mut self) {
+        debug!("Rotating token secret");
+        self.previous = self.current;
+        self.current = random_secret();
+    }
+}
+
+fn random_secret() -> [u8; 20] {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill(&mut secret);
+    secret
+}
+
+fn make_token<TAddr: Hash>(secret: &[u8], address: &TAddr) -> Token {
+    let mut hasher = Sha1Hasher(Sha1::new());
+    hasher.write(secret);
+    address.hash(&mut hasher);
+    hasher.0.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+
+    use super::TokenSecrets;
+
+    #[test]
+    fn test_token_for_address() {
+        let mut secrets = TokenSecrets::new();
+        let addr1: net::SocketAddr = "127.0.0.1:8008".parse().unwrap();
+        let addr2: net::SocketAddr = "127.0.0.1:8009".parse().unwrap();
+        let token = secrets.generate(&addr1);
+        assert!(secrets.verify(&addr1, &token));
+        assert!(!secrets.verify(&addr2, &token));
+        assert!(!secrets.verify(&addr1, &token[1..]));
+    }
+
+    #[test]
+    fn test_token_expires() {
+        let mut secrets = TokenSecrets::new();
+        let addr: net::SocketAddr = "127.0.0.1:8008".parse().unwrap();
+        let token = secrets.generate(&addr);
+        secrets.rotate();
+        assert!(secrets.verify(&addr, &token));
+        secrets.rotate();
+        assert!(!secrets.verify(&addr, &token));
+    }
+}